@@ -0,0 +1,117 @@
+use std::fmt;
+
+use crate::Level;
+
+/// Everything that can go wrong while building, loading or sampling a model.
+#[derive(Debug)]
+pub enum MarkovError {
+    /// The input text contains no tokens at all.
+    EmptyInput,
+    /// The input text is too short to form even a single transition at the requested state size.
+    InsufficientWords {
+        /// Number of tokens in the longest input document.
+        found: usize,
+        /// Number of tokens needed for a single transition.
+        needed: usize,
+        /// The requested state size.
+        state_size: usize,
+        /// The tokenization level, for naming the unit in messages.
+        level: Level,
+    },
+    /// A state in a model file doesn't have as many tokens as the model's state size says.
+    StateSizeMismatch {
+        /// The state size stored in the model.
+        state_size: usize,
+        /// Number of tokens in the offending state.
+        found: usize,
+        /// The offending state.
+        state: String,
+    },
+    /// The model has no states to generate from.
+    EmptyModel,
+    /// A state in the model has no successors.
+    EmptyState(String),
+    /// A generation option is out of range or conflicts with another option.
+    InvalidOption(String),
+    /// The prompt contains no tokens.
+    EmptyPrompt,
+    /// The end of the prompt isn't a state in the model.
+    PromptNotFound(String),
+    /// The model file was written by an older, unversioned release.
+    LegacyFormat,
+    /// The model file uses a format version this release can't read.
+    UnsupportedVersion(u32),
+    /// Reading or decompressing data failed.
+    Io(std::io::Error),
+    /// Serializing or deserializing a model failed.
+    Serde(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl fmt::Display for MarkovError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MarkovError::EmptyInput => write!(f, "Input text is empty"),
+            MarkovError::InsufficientWords { found, needed, state_size, level } => {
+                write!(f, "Input text has {} {unit}, but need at least {} {unit} for state size {}",
+                       found, needed, state_size, unit = level.unit())
+            }
+            MarkovError::StateSizeMismatch { state_size, found, state } => {
+                write!(f, "Model has state size {}, but its state '{}' has {} tokens", state_size, state, found)
+            }
+            MarkovError::EmptyModel => write!(f, "Model is empty, cannot generate text"),
+            MarkovError::EmptyState(state) => write!(f, "No words available for state '{}'", state),
+            MarkovError::InvalidOption(message) => write!(f, "{}", message),
+            MarkovError::EmptyPrompt => write!(f, "Prompt is empty"),
+            MarkovError::PromptNotFound(state) => write!(f, "The end of the prompt, '{}', is not a state in the model", state),
+            MarkovError::LegacyFormat => {
+                write!(f, "Model uses the old unversioned format, please regenerate it with the `generate` command")
+            }
+            MarkovError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported model format version {} (expected {}), please regenerate it with the `generate` command",
+                       version, crate::MODEL_FORMAT_VERSION)
+            }
+            MarkovError::Io(e) => write!(f, "{}", e),
+            MarkovError::Serde(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for MarkovError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MarkovError::Io(e) => Some(e),
+            MarkovError::Serde(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for MarkovError {
+    fn from(e: std::io::Error) -> Self {
+        MarkovError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for MarkovError {
+    fn from(e: serde_json::Error) -> Self {
+        MarkovError::Serde(Box::new(e))
+    }
+}
+
+impl From<bincode::error::EncodeError> for MarkovError {
+    fn from(e: bincode::error::EncodeError) -> Self {
+        MarkovError::Serde(Box::new(e))
+    }
+}
+
+impl From<bincode::error::DecodeError> for MarkovError {
+    fn from(e: bincode::error::DecodeError) -> Self {
+        MarkovError::Serde(Box::new(e))
+    }
+}
+
+impl From<std::str::Utf8Error> for MarkovError {
+    fn from(e: std::str::Utf8Error) -> Self {
+        MarkovError::Serde(Box::new(e))
+    }
+}
@@ -0,0 +1,3516 @@
+//! Markov chain text generation.
+//!
+//! Train a [`MarkovModel`] on some input text with [`MarkovModel::train`], then
+//! sample new text from it with [`MarkovModel::generate`]. The free functions
+//! underneath, such as [`generate_markov_chain`] and [`generate_text`], are
+//! public too.
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::hash_map::Entry;
+#[cfg(feature = "fs")]
+use std::fs;
+use std::hash::{BuildHasher, Hash};
+use std::io::{Read, Write};
+use std::ops::Range;
+#[cfg(feature = "fs")]
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{self, AtomicBool};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use rand::Rng;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use rustc_hash::{FxBuildHasher, FxHashMap, FxHashSet};
+use rand::distr::uniform::SampleUniform;
+use rand::distr::weighted::Weight;
+use rand::seq::{IndexedRandom, SliceRandom};
+use serde::{Deserialize, Serialize};
+use unicode_segmentation::UnicodeSegmentation;
+
+mod error;
+
+pub use error::MarkovError;
+
+/// Current version of the serialized model format.
+///
+/// Version 1 (unversioned) stored every observed successor as a separate list entry.
+/// Version 2 stores each distinct successor once along with how often it was seen.
+/// Version 3 also records the state size the model was built with.
+/// Version 4 also records the tokenization [`Level`]; version 3 models are read as word-level.
+/// Version 5 also records the [`Tokenization`]; older models are read as whitespace-tokenized.
+/// Version 6 also records whether the input was lowercased; older models are read as not lowercased.
+/// Version 7 also records whether documents were wrapped in sentinels; older models are read as without.
+/// Version 8 also records whether the chain was built backwards; older models are read as forwards.
+/// Version 9 also records the markup, input mode, paragraphs, stopwords and token filters; older models are read
+/// as continuous text without any.
+pub const MODEL_FORMAT_VERSION: u32 = 9;
+
+/// First model format version that records every [`BuildOptions`] that shapes the chain.
+const COMPLETE_BUILD_OPTIONS_VERSION: u32 = 9;
+
+/// Model format versions that can still be read.
+const SUPPORTED_MODEL_FORMAT_VERSIONS: std::ops::RangeInclusive<u32> = 3..=MODEL_FORMAT_VERSION;
+
+/// What a single token of the chain is.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    /// Whitespace-separated words, joined back together with spaces.
+    #[default]
+    Word,
+    /// Individual characters, joined back together with nothing in between.
+    Char,
+}
+
+impl Level {
+    /// The string placed between tokens, both in state keys and in generated text.
+    pub fn separator(self) -> &'static str {
+        match self {
+            Level::Word => " ",
+            Level::Char => "",
+        }
+    }
+
+    /// The sentinel token placed before every document by [`BuildOptions::sentinels`].
+    pub fn start_token(self) -> &'static str {
+        match self {
+            Level::Word => "<START>",
+            Level::Char => "\u{2}",
+        }
+    }
+
+    /// The sentinel token placed after every document by [`BuildOptions::sentinels`].
+    pub fn end_token(self) -> &'static str {
+        match self {
+            Level::Word => "<END>",
+            Level::Char => "\u{3}",
+        }
+    }
+
+    /// Whether `token` is one of the sentinels of this level.
+    fn is_sentinel(self, token: &str) -> bool {
+        token == self.start_token() || token == self.end_token()
+    }
+
+    /// Plural name of a token at this level, for messages.
+    pub fn unit(self) -> &'static str {
+        match self {
+            Level::Word => "words",
+            Level::Char => "characters",
+        }
+    }
+}
+
+/// How word-level input is split into tokens.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum Tokenization {
+    /// Split on whitespace only, so punctuation stays attached to words.
+    #[default]
+    Whitespace,
+    /// Also split punctuation off words into tokens of their own, keeping contractions and hyphenated words whole,
+    /// and join them back without the extra spaces.
+    Punctuation,
+}
+
+/// A Markov chain: maps each state to its distinct successors and how often each was seen,
+/// in the order they were first encountered.
+pub type Chain = HashMap<String, Vec<(String, u32)>>;
+
+/// File format a model is stored in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ModelFormat {
+    /// Human-readable JSON.
+    #[default]
+    Json,
+    /// Compact binary encoding using bincode, starting with [`BINCODE_MAGIC`].
+    Bincode,
+}
+
+/// Bytes every binary model file starts with, so it can be told apart from a JSON one.
+pub const BINCODE_MAGIC: &[u8; 4] = b"MKVB";
+
+/// A trained model: the chain together with the settings it was built with.
+#[derive(Clone, Debug, Default)]
+pub struct MarkovModel {
+    /// The transitions between states.
+    pub chain: Chain,
+    /// Number of tokens in each state.
+    pub state_size: usize,
+    /// What a single token is.
+    pub level: Level,
+    /// How word-level input was split into tokens.
+    pub tokenization: Tokenization,
+    /// Whether the input was lowercased before building the chain.
+    pub lowercase: bool,
+    /// Whether every document was wrapped in start and end sentinels, see [`BuildOptions::sentinels`].
+    pub sentinels: bool,
+    /// Whether the chain was built backwards, see [`BuildOptions::reverse`].
+    pub reversed: bool,
+    /// Markup removed from the input, see [`BuildOptions::strip_markup`].
+    pub strip_markup: Markup,
+    /// How the input was divided into documents, see [`BuildOptions::mode`].
+    pub mode: InputMode,
+    /// Whether every paragraph was a document of its own, see [`BuildOptions::respect_paragraphs`].
+    pub respect_paragraphs: bool,
+    /// The stopwords left out of the chain, sorted, see [`BuildOptions::stopwords`].
+    pub stopwords: Vec<String>,
+    /// What was done with the `stopwords`.
+    pub stopword_mode: StopwordMode,
+    /// Kinds of tokens left out of the chain, see [`BuildOptions::filters`].
+    pub filters: Vec<TokenFilter>,
+    /// What was done with the tokens caught by `filters`.
+    pub filter_mode: StopwordMode,
+    /// Whether the model was read from a file too old to record all of the above.
+    legacy_options: bool,
+    /// The starters of [`StartStrategy::Capital`], worked out the first time they're needed.
+    starters: OnceLock<Vec<String>>,
+    /// Every state keyed by its lowercased form, for [`GenerateOptions::case_insensitive_lookup`].
+    lowercase_states: OnceLock<HashMap<String, String>>,
+}
+
+impl MarkovModel {
+    fn file(&self) -> ModelFile<'_> {
+        ModelFile {
+            version: MODEL_FORMAT_VERSION,
+            state_size: self.state_size,
+            level: self.level,
+            tokenization: self.tokenization,
+            lowercase: self.lowercase,
+            sentinels: self.sentinels,
+            reversed: self.reversed,
+            strip_markup: self.strip_markup,
+            mode: self.mode,
+            respect_paragraphs: self.respect_paragraphs,
+            stopwords: Cow::Borrowed(&self.stopwords),
+            stopword_mode: self.stopword_mode,
+            filters: Cow::Borrowed(&self.filters),
+            filter_mode: self.filter_mode,
+            states: States::Chain(Cow::Borrowed(&self.chain)),
+        }
+    }
+
+    /// The transitions between states, for changing them.
+    ///
+    /// The starting states generation picks from, and the lowercased states of
+    /// [`GenerateOptions::case_insensitive_lookup`], are worked out once per model, the first time they're needed,
+    /// and kept for every later generation. Going through here forgets them again, so that they're worked out
+    /// anew from the changed chain. Change [`MarkovModel::chain`] directly only before generating from the model.
+    pub fn chain_mut(&mut self) -> &mut Chain {
+        self.starters = OnceLock::new();
+        self.lowercase_states = OnceLock::new();
+        &mut self.chain
+    }
+
+    /// The starters of [`StartStrategy::Capital`], see [`MarkovModel::chain_mut`].
+    fn capital_starters(&self) -> &[String] {
+        self.starters.get_or_init(|| find_capital_starters(self))
+    }
+
+    /// The state that lowercases to the same as `state`, if any. Of several, the one seen most often wins.
+    fn find_state_ignoring_case(&self, state: &str) -> Option<&str> {
+        self.lowercase_states.get_or_init(|| {
+            let mut states: HashMap<String, String> = HashMap::new();
+            let seen = |state: &str| -> u64 { self.chain[state].iter().map(|(_, count)| *count as u64).sum() };
+            for key in self.chain.keys() {
+                match states.entry(key.to_lowercase()) {
+                    Entry::Occupied(mut entry) => {
+                        // Ties go to the smaller state, so the choice doesn't depend on the order of the chain
+                        let best = entry.get();
+                        if (seen(key), std::cmp::Reverse(key)) > (seen(best), std::cmp::Reverse(best)) {
+                            entry.insert(key.clone());
+                        }
+                    }
+                    Entry::Vacant(entry) => {
+                        entry.insert(key.clone());
+                    }
+                }
+            }
+            states
+        }).get(&state.to_lowercase()).map(String::as_str)
+    }
+
+    /// The options the model was built with, for building more text into it the same way.
+    ///
+    /// Models read from files older than format version 9 don't record the markup, input mode, paragraphs,
+    /// stopwords or filters, so these are left at their defaults, see [`MarkovModel::has_complete_build_options`].
+    pub fn build_options(&self) -> BuildOptions {
+        BuildOptions {
+            level: self.level,
+            tokenization: self.tokenization,
+            strip_markup: self.strip_markup,
+            stopwords: self.stopwords.iter().cloned().collect(),
+            stopword_mode: self.stopword_mode,
+            filters: self.filters.clone(),
+            filter_mode: self.filter_mode,
+            lowercase: self.lowercase,
+            sentinels: self.sentinels,
+            reverse: self.reversed,
+            respect_paragraphs: self.respect_paragraphs,
+            mode: self.mode,
+            ..BuildOptions::default()
+        }
+    }
+
+    /// Whether [`MarkovModel::build_options`] are all the options that shaped the chain, rather than a guess
+    /// for a model read from an older file.
+    pub fn has_complete_build_options(&self) -> bool {
+        !self.legacy_options
+    }
+
+    /// Split `input` into tokens the same way the model's training input was.
+    pub fn tokenize(&self, input: &str) -> Vec<String> {
+        tokenize(input, &self.build_options())
+    }
+
+    /// Build a word-level model from `text` with the default [`BuildOptions`].
+    pub fn train(text: &str, state_size: usize) -> Result<Self, MarkovError> {
+        Self::train_with(text, state_size, &BuildOptions::default())
+    }
+
+    /// Build a model from `text` with the given options.
+    pub fn train_with(text: &str, state_size: usize, options: &BuildOptions) -> Result<Self, MarkovError> {
+        Self::from_documents(&tokenize_documents(text, options), state_size, options)
+    }
+
+    /// Build a model from documents that were already split into tokens, see [`build_chain`].
+    pub fn from_documents(documents: &[Vec<String>], state_size: usize, options: &BuildOptions) -> Result<Self, MarkovError> {
+        Ok(CompactModel::build(documents, state_size, options)?.into_model())
+    }
+
+    /// Generate up to `max_words` tokens of text with the default [`GenerateOptions`].
+    pub fn generate<R: Rng + ?Sized>(&self, max_words: usize, rng: &mut R) -> Result<String, MarkovError> {
+        let options = GenerateOptions { max_words, ..GenerateOptions::default() };
+        generate_text(self, &options, rng)
+    }
+
+    /// Generate text with the given options, along with how it was generated.
+    pub fn generate_with<R: Rng + ?Sized>(&self, options: &GenerateOptions, rng: &mut R) -> Result<GeneratedText, MarkovError> {
+        generate_text_with_metadata(self, options, rng)
+    }
+
+    /// Write the model to `path` as JSON, gzip-compressed if the name ends in `.gz`.
+    #[cfg(feature = "fs")]
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), MarkovError> {
+        self.save_as(path, ModelFormat::Json, false)
+    }
+
+    /// Write the model to `path` in the given format, gzip-compressed if `compress` is set
+    /// or the name ends in `.gz`.
+    #[cfg(feature = "fs")]
+    pub fn save_as(&self, path: impl AsRef<Path>, format: ModelFormat, compress: bool) -> Result<(), MarkovError> {
+        write_model_file(path.as_ref(), model_to_bytes(self, format)?, compress)
+    }
+
+    /// Read a model from `path`, in any format [`model_from_bytes`] understands.
+    #[cfg(feature = "fs")]
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, MarkovError> {
+        model_from_bytes(&fs::read(path)?)
+    }
+}
+
+impl From<ModelFile<'_>> for MarkovModel {
+    fn from(file: ModelFile<'_>) -> Self {
+        MarkovModel {
+            chain: file.states.into_chain(),
+            state_size: file.state_size,
+            level: file.level,
+            tokenization: file.tokenization,
+            lowercase: file.lowercase,
+            sentinels: file.sentinels,
+            reversed: file.reversed,
+            strip_markup: file.strip_markup,
+            mode: file.mode,
+            respect_paragraphs: file.respect_paragraphs,
+            stopwords: file.stopwords.into_owned(),
+            stopword_mode: file.stopword_mode,
+            filters: file.filters.into_owned(),
+            filter_mode: file.filter_mode,
+            legacy_options: file.version < COMPLETE_BUILD_OPTIONS_VERSION,
+            starters: OnceLock::new(),
+            lowercase_states: OnceLock::new(),
+        }
+    }
+}
+
+impl MarkovModel {
+    /// Check that every state really has the stored state size, so a damaged or hand-edited file
+    /// is rejected instead of silently generating nothing.
+    fn checked(self) -> Result<Self, MarkovError> {
+        if let Some(state) = self.chain.keys().find(|state| split_state(state, self.level).len() != self.state_size) {
+            return Err(MarkovError::StateSizeMismatch {
+                state_size: self.state_size,
+                found: split_state(state, self.level).len(),
+                state: state.clone(),
+            });
+        }
+        Ok(self)
+    }
+
+    /// Everything wrong with the model, in order of the states, or nothing if it's fine to generate from.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.chain.is_empty() {
+            problems.push("Model has no states".to_string());
+        }
+
+        let mut states: Vec<&String> = self.chain.keys().collect();
+        states.sort();
+        for state in states {
+            let tokens = split_state(state, self.level).len();
+            if tokens != self.state_size {
+                problems.push(format!("State '{}' has {} {}, but the state size is {}", state, tokens, self.level.unit(), self.state_size));
+            }
+            let successors = &self.chain[state];
+            if successors.is_empty() {
+                problems.push(format!("State '{}' has no successors", state));
+            }
+            for (word, count) in successors {
+                if word.is_empty() {
+                    problems.push(format!("State '{}' has an empty successor", state));
+                }
+                if *count == 0 {
+                    problems.push(format!("State '{}' has successor '{}' with a count of 0", state, word));
+                }
+            }
+        }
+        problems
+    }
+}
+
+/// On-disk representation of a model.
+#[derive(Serialize, Deserialize)]
+struct ModelFile<'a> {
+    version: u32,
+    state_size: usize,
+    #[serde(default)]
+    level: Level,
+    #[serde(default)]
+    tokenization: Tokenization,
+    #[serde(default)]
+    lowercase: bool,
+    #[serde(default)]
+    sentinels: bool,
+    #[serde(default)]
+    reversed: bool,
+    #[serde(default)]
+    strip_markup: Markup,
+    #[serde(default)]
+    mode: InputMode,
+    #[serde(default)]
+    respect_paragraphs: bool,
+    #[serde(default)]
+    stopwords: Cow<'a, [String]>,
+    #[serde(default)]
+    stopword_mode: StopwordMode,
+    #[serde(default)]
+    filters: Cow<'a, [TokenFilter]>,
+    #[serde(default)]
+    filter_mode: StopwordMode,
+    #[serde(with = "successor_counts")]
+    states: States<'a>,
+}
+
+/// The states of a model file: a plain chain, or the interned states of a [`CompactModel`] being saved.
+enum States<'a> {
+    Chain(Cow<'a, Chain>),
+    Compact(&'a CompactModel),
+}
+
+impl States<'_> {
+    fn into_chain(self) -> Chain {
+        match self {
+            States::Chain(chain) => chain.into_owned(),
+            States::Compact(model) => model.to_chain(),
+        }
+    }
+}
+
+/// Just enough of a model file to tell which format version it uses.
+#[derive(Deserialize)]
+struct VersionOnly {
+    version: u32,
+}
+
+/// Serializes each state's successors as a `{"word": count}` object, keeping their order.
+mod successor_counts {
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+    use std::fmt;
+    use serde::de::{MapAccess, Visitor};
+    use serde::ser::Serializer;
+    use serde::{Deserialize, Deserializer, Serialize};
+    use super::{Interner, States};
+
+    struct Counts<'a>(&'a [(String, u32)]);
+
+    impl Serialize for Counts<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_map(self.0.iter().map(|(word, count)| (word, count)))
+        }
+    }
+
+    struct InternedCounts<'a>(&'a [(u32, u32)], &'a Interner);
+
+    impl Serialize for InternedCounts<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_map(self.0.iter().map(|&(id, count)| (self.1.token(id), count)))
+        }
+    }
+
+    struct OwnedCounts(Vec<(String, u32)>);
+
+    impl<'de> Deserialize<'de> for OwnedCounts {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            struct CountsVisitor;
+
+            impl<'de> Visitor<'de> for CountsVisitor {
+                type Value = OwnedCounts;
+
+                fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    f.write_str("a map of successor words to counts")
+                }
+
+                fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                    let mut counts = Vec::with_capacity(map.size_hint().unwrap_or(0));
+                    while let Some(entry) = map.next_entry()? {
+                        counts.push(entry);
+                    }
+                    Ok(OwnedCounts(counts))
+                }
+            }
+
+            deserializer.deserialize_map(CountsVisitor)
+        }
+    }
+
+    /// States are written in sorted order, so the same model always gives the same file. Successors keep
+    /// their order, since sampling depends on it.
+    pub fn serialize<S: Serializer>(states: &States, serializer: S) -> Result<S::Ok, S::Error> {
+        match states {
+            States::Chain(chain) => {
+                let mut sorted: Vec<_> = chain.iter().collect();
+                sorted.sort_unstable_by_key(|(state, _)| *state);
+                serializer.collect_map(sorted.into_iter().map(|(state, successors)| (state, Counts(successors))))
+            }
+            States::Compact(model) => {
+                // Comparing token by token sorts like the joined states, without joining them all up front
+                let tokens = &model.tokens;
+                let mut sorted: Vec<_> = model.states.iter().collect();
+                sorted.sort_unstable_by(|(a, _), (b, _)| {
+                    a.iter().map(|&id| tokens.token(id)).cmp(b.iter().map(|&id| tokens.token(id)))
+                });
+                serializer.collect_map(sorted.into_iter()
+                    .map(|(state, successors)| (tokens.join(state, model.level), InternedCounts(successors, tokens))))
+            }
+        }
+    }
+
+    pub fn deserialize<'de, 'a, D: Deserializer<'de>>(deserializer: D) -> Result<States<'a>, D::Error> {
+        let states = HashMap::<String, OwnedCounts>::deserialize(deserializer)?;
+        Ok(States::Chain(Cow::Owned(states.into_iter().map(|(state, counts)| (state, counts.0)).collect())))
+    }
+}
+
+/// Serialize `model` into the current versioned JSON model format.
+pub fn model_to_json(model: &MarkovModel) -> Result<String, MarkovError> {
+    Ok(serde_json::to_string(&model.file())?)
+}
+
+/// Parse a model previously written by [`model_to_json`].
+///
+/// Models in an older or newer format are rejected rather than misread.
+pub fn model_from_json(data: &str) -> Result<MarkovModel, MarkovError> {
+    parse_json_model(data)?.checked()
+}
+
+/// Parse a JSON model without checking that its states match its state size.
+fn parse_json_model(data: &str) -> Result<MarkovModel, MarkovError> {
+    match serde_json::from_str::<ModelFile>(data) {
+        Ok(file) if SUPPORTED_MODEL_FORMAT_VERSIONS.contains(&file.version) => Ok(file.into()),
+        Ok(file) => Err(MarkovError::UnsupportedVersion(file.version)),
+        Err(e) => {
+            if let Ok(file) = serde_json::from_str::<VersionOnly>(data)
+                && !SUPPORTED_MODEL_FORMAT_VERSIONS.contains(&file.version) {
+                return Err(MarkovError::UnsupportedVersion(file.version));
+            }
+            if serde_json::from_str::<HashMap<String, Vec<String>>>(data).is_ok() {
+                return Err(MarkovError::LegacyFormat);
+            }
+            Err(e.into())
+        }
+    }
+}
+
+/// Serialize `model` in the given file `format`.
+pub fn model_to_bytes(model: &MarkovModel, format: ModelFormat) -> Result<Vec<u8>, MarkovError> {
+    file_to_bytes(&model.file(), format)
+}
+
+fn file_to_bytes(file: &ModelFile, format: ModelFormat) -> Result<Vec<u8>, MarkovError> {
+    match format {
+        ModelFormat::Json => Ok(serde_json::to_vec(file)?),
+        ModelFormat::Bincode => {
+            let mut data = BINCODE_MAGIC.to_vec();
+            data.extend(bincode::serde::encode_to_vec(file, bincode::config::standard())?);
+            Ok(data)
+        }
+    }
+}
+
+/// Write serialized model `data` to `path`, gzip-compressed if `compress` is set or the name ends in `.gz`.
+#[cfg(feature = "fs")]
+fn write_model_file(path: &Path, mut data: Vec<u8>, compress: bool) -> Result<(), MarkovError> {
+    if compress || path.extension().is_some_and(|extension| extension == "gz") {
+        data = gzip(&data)?;
+    }
+    fs::write(path, data)?;
+    Ok(())
+}
+
+/// Bytes every gzip stream starts with.
+const GZIP_MAGIC: &[u8; 2] = &[0x1f, 0x8b];
+
+/// Gzip-compress a serialized model. [`model_from_bytes`] decompresses it again transparently.
+pub fn gzip(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// The format of a model written by [`model_to_bytes`], and whether it was [`gzip`]-compressed since.
+pub fn detect_format(data: &[u8]) -> Result<(ModelFormat, bool), MarkovError> {
+    let compressed = data.starts_with(GZIP_MAGIC);
+    let mut magic = Vec::new();
+    if compressed {
+        GzDecoder::new(data).take(BINCODE_MAGIC.len() as u64).read_to_end(&mut magic)?;
+    } else {
+        magic.extend(data.iter().take(BINCODE_MAGIC.len()));
+    }
+    let format = if magic == BINCODE_MAGIC { ModelFormat::Bincode } else { ModelFormat::Json };
+    Ok((format, compressed))
+}
+
+/// Parse a model written by [`model_to_bytes`] in any format.
+///
+/// Gzip-compressed data is decompressed first. Binary models are recognised by their leading
+/// [`BINCODE_MAGIC`], anything else is parsed as JSON.
+pub fn model_from_bytes(data: &[u8]) -> Result<MarkovModel, MarkovError> {
+    parse_model(data)?.checked()
+}
+
+/// Parse a model in any format and report all of its [`MarkovModel::problems`], rather than failing on the first.
+///
+/// Data that can't be parsed as a model at all is still an error.
+pub fn validate_model(data: &[u8]) -> Result<Vec<String>, MarkovError> {
+    Ok(parse_model(data)?.problems())
+}
+
+/// Parse a model in any format without checking that its states match its state size.
+fn parse_model(data: &[u8]) -> Result<MarkovModel, MarkovError> {
+    if data.starts_with(GZIP_MAGIC) {
+        let mut decompressed = Vec::new();
+        GzDecoder::new(data).read_to_end(&mut decompressed)?;
+        return parse_model(&decompressed);
+    }
+
+    let Some(data) = data.strip_prefix(BINCODE_MAGIC) else {
+        return parse_json_model(std::str::from_utf8(data)?);
+    };
+
+    let config = bincode::config::standard();
+    match bincode::serde::decode_from_slice::<ModelFile, _>(data, config) {
+        Ok((file, _)) if SUPPORTED_MODEL_FORMAT_VERSIONS.contains(&file.version) => Ok(file.into()),
+        Ok((file, _)) => Err(MarkovError::UnsupportedVersion(file.version)),
+        Err(e) => {
+            if let Ok((file, _)) = bincode::serde::decode_from_slice::<VersionOnly, _>(data, config)
+                && !SUPPORTED_MODEL_FORMAT_VERSIONS.contains(&file.version) {
+                return Err(MarkovError::UnsupportedVersion(file.version));
+            }
+            Err(e.into())
+        }
+    }
+}
+
+/// Fold the transitions of `other` into `model`, adding up the counts of successors both have seen.
+///
+/// Successors new to a state are appended after the ones it already has. Counts stop at [`u32::MAX`]
+/// instead of overflowing.
+pub fn merge_chain(model: &mut Chain, other: Chain) {
+    merge_states(model, other);
+}
+
+/// [`merge_chain`] for any kind of state and token, such as the interned ones of a [`CompactModel`].
+fn merge_states<K: Eq + Hash, T: PartialEq, S: BuildHasher>(model: &mut HashMap<K, Vec<(T, u32)>, S>, other: HashMap<K, Vec<(T, u32)>, S>) {
+    for (state, other_successors) in other {
+        let successors = model.entry(state).or_default();
+        for (word, count) in other_successors {
+            match successors.iter_mut().find(|(existing, _)| *existing == word) {
+                Some((_, existing_count)) => *existing_count = existing_count.saturating_add(count),
+                None => successors.push((word, count)),
+            }
+        }
+    }
+}
+
+/// What was removed from a chain by pruning.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Pruned {
+    /// Number of states removed because none of their successors were kept.
+    pub states: usize,
+    /// Number of transitions removed, i.e. the sum of the counts of all removed successors.
+    pub transitions: u64,
+}
+
+/// Drop every successor seen fewer than `min_count` times, and every state left without successors.
+pub fn prune_chain(model: &mut Chain, min_count: u32) -> Pruned {
+    prune_states(model, min_count)
+}
+
+/// [`prune_chain`] for any kind of state and token.
+fn prune_states<K, T, S>(model: &mut HashMap<K, Vec<(T, u32)>, S>, min_count: u32) -> Pruned {
+    let mut pruned = Pruned::default();
+    model.retain(|_, successors| {
+        successors.retain(|(_, count)| {
+            let keep = *count >= min_count;
+            if !keep {
+                pruned.transitions += *count as u64;
+            }
+            keep
+        });
+        let keep = !successors.is_empty();
+        if !keep {
+            pruned.states += 1;
+        }
+        keep
+    });
+    pruned
+}
+
+/// Keep only the `max_successors` most frequent successors of every state, ties going to the one seen first,
+/// and drop every state left without successors.
+///
+/// The kept successors keep their counts and order, so they're sampled in the same proportions to each other as before.
+pub fn cap_successors(model: &mut Chain, max_successors: usize) -> Pruned {
+    cap_states(model, max_successors)
+}
+
+/// [`cap_successors`] for any kind of state and token.
+fn cap_states<K, T, S>(model: &mut HashMap<K, Vec<(T, u32)>, S>, max_successors: usize) -> Pruned {
+    let mut pruned = Pruned::default();
+    model.retain(|_, successors| {
+        if successors.len() > max_successors {
+            // A stable sort, so ties keep their order
+            let mut ranked: Vec<usize> = (0..successors.len()).collect();
+            ranked.sort_by(|&a, &b| successors[b].1.cmp(&successors[a].1));
+            let mut keep = vec![false; successors.len()];
+            for &i in &ranked[..max_successors] {
+                keep[i] = true;
+            }
+            let mut keep = keep.into_iter();
+            successors.retain(|(_, count)| {
+                let keep = keep.next().unwrap_or(false);
+                if !keep {
+                    pruned.transitions += *count as u64;
+                }
+                keep
+            });
+        }
+        let keep = !successors.is_empty();
+        if !keep {
+            pruned.states += 1;
+        }
+        keep
+    });
+    pruned
+}
+
+/// Put the successors of every state in a random order, keeping their counts.
+///
+/// Sampling only depends on the counts, so this shouldn't change how generated text is distributed, only which
+/// text a given seed gives. It's a debugging aid for catching code that depends on the order of successors.
+pub fn shuffle_successors<R: Rng + ?Sized>(model: &mut Chain, rng: &mut R) {
+    // Go through the states in order, so the shuffle only depends on the RNG, not on HashMap iteration order
+    let mut states: Vec<(&String, &mut Vec<(String, u32)>)> = model.iter_mut().collect();
+    states.sort_unstable_by_key(|(state, _)| *state);
+    for (_, successors) in states {
+        successors.shuffle(rng);
+    }
+}
+
+/// Summary statistics about a chain, see [`model_stats`].
+#[derive(Clone, Debug, Serialize)]
+pub struct ModelStats {
+    /// Number of states.
+    pub states: usize,
+    /// Total number of recorded transitions, i.e. the sum of all successor counts.
+    pub transitions: u64,
+    /// Mean number of distinct successors per state.
+    pub mean_successors: f64,
+    /// Median number of distinct successors per state.
+    pub median_successors: f64,
+    /// Largest number of distinct successors of any state.
+    pub max_successors: usize,
+    /// Number of states with only a single distinct successor.
+    pub dead_ends: usize,
+    /// The states with the most distinct successors, most connected first, with their successor counts.
+    pub most_connected: Vec<(String, usize)>,
+}
+
+/// How many states [`model_stats`] lists in [`ModelStats::most_connected`].
+pub const MOST_CONNECTED_STATES: usize = 10;
+
+/// Compute summary statistics about `model`.
+pub fn model_stats(model: &Chain) -> ModelStats {
+    let mut successor_counts: Vec<usize> = model.values().map(Vec::len).collect();
+    successor_counts.sort_unstable();
+
+    let states = model.len();
+    let median_successors = match states {
+        0 => 0.0,
+        n if n % 2 == 0 => (successor_counts[n / 2 - 1] + successor_counts[n / 2]) as f64 / 2.0,
+        n => successor_counts[n / 2] as f64,
+    };
+
+    let mut most_connected: Vec<(String, usize)> = model.iter()
+        .map(|(state, successors)| (state.clone(), successors.len()))
+        .collect();
+    most_connected.sort_by(|(a_state, a_len), (b_state, b_len)| b_len.cmp(a_len).then_with(|| a_state.cmp(b_state)));
+    most_connected.truncate(MOST_CONNECTED_STATES);
+
+    ModelStats {
+        states,
+        transitions: model.values().flatten().map(|(_, count)| *count as u64).sum(),
+        mean_successors: if states == 0 { 0.0 } else { successor_counts.iter().sum::<usize>() as f64 / states as f64 },
+        median_successors,
+        max_successors: successor_counts.last().copied().unwrap_or(0),
+        dead_ends: successor_counts.iter().filter(|len| **len == 1).count(),
+        most_connected,
+    }
+}
+
+/// How much the states of two chains overlap, see [`compare_chains`].
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct ModelOverlap {
+    /// Number of states both chains have.
+    pub shared: usize,
+    /// Number of states only the first chain has.
+    pub only_a: usize,
+    /// Number of states only the second chain has.
+    pub only_b: usize,
+    /// The Jaccard index of the two sets of states: shared states over all distinct states, from 0 to 1.
+    pub similarity: f64,
+}
+
+/// Compare the states of `a` and `b`, regardless of their successors.
+pub fn compare_chains(a: &Chain, b: &Chain) -> ModelOverlap {
+    let shared = a.keys().filter(|state| b.contains_key(*state)).count();
+    let union = a.len() + b.len() - shared;
+    ModelOverlap {
+        shared,
+        only_a: a.len() - shared,
+        only_b: b.len() - shared,
+        similarity: if union == 0 { 0.0 } else { shared as f64 / union as f64 },
+    }
+}
+
+/// How often every distinct token occurs in `documents`, most frequent first and ties in alphabetical order.
+pub fn token_frequencies(documents: &[Vec<String>]) -> Vec<(String, usize)> {
+    ngram_frequencies(documents, 1, Level::Word)
+}
+
+/// How often every distinct run of `n` consecutive tokens occurs in `documents`, joined with the level's
+/// [`Level::separator`], most frequent first and ties in alphabetical order.
+///
+/// Like states, n-grams never span two documents.
+pub fn ngram_frequencies(documents: &[Vec<String>], n: usize, level: Level) -> Vec<(String, usize)> {
+    let mut counts: HashMap<&[String], usize> = HashMap::new();
+    for ngram in documents.iter().flat_map(|tokens| tokens.windows(n.max(1))) {
+        *counts.entry(ngram).or_default() += 1;
+    }
+    let mut frequencies: Vec<(String, usize)> = counts.into_iter()
+        .map(|(ngram, count)| (ngram.join(level.separator()), count))
+        .collect();
+    frequencies.sort_by(|(a_ngram, a_count), (b_ngram, b_count)| b_count.cmp(a_count).then_with(|| a_ngram.cmp(b_ngram)));
+    frequencies
+}
+
+/// Render `model` as a GraphViz DOT digraph, for `dot -Tpng` and friends.
+///
+/// Every state is a node, with an edge labelled with the count to the state each of its successors
+/// leads to. Transitions seen fewer than `min_count` times are left out.
+pub fn model_to_dot(model: &MarkovModel, min_count: u32) -> String {
+    let quote = |text: &str| format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""));
+
+    let mut states: Vec<&String> = model.chain.keys().collect();
+    states.sort();
+
+    let mut dot = String::from("digraph markov {\n");
+    for state in states {
+        let tokens = split_state(state, model.level);
+        for (successor, count) in &model.chain[state] {
+            if *count < min_count {
+                continue;
+            }
+            // An order 0 model only has the empty state, which every successor leads back to
+            let next = tokens.iter().skip(1).chain(std::iter::once(successor)).take(model.state_size).cloned().collect::<Vec<_>>();
+            dot.push_str(&format!("    {} -> {} [label={}];\n", quote(state), quote(&next.join(model.level.separator())), count));
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// Split `input` into the tokens the chain is built from.
+///
+/// Markup is stripped first, according to [`BuildOptions::strip_markup`]. At [`Level::Char`] every grapheme
+/// cluster is a token, so an emoji with a skin tone or a letter with combining accents stays a single character,
+/// with each run of whitespace collapsed into a single space, and the `tokenization` option is ignored.
+pub fn tokenize(input: &str, options: &BuildOptions) -> Vec<String> {
+    // Editors on Windows like to start files with a byte order mark, which would otherwise stick to the
+    // first token. Line endings need no such care, as `\r` is whitespace like `\n`.
+    let input = input.strip_prefix('\u{FEFF}').unwrap_or(input);
+    let input = strip_markup(input, options.strip_markup);
+    let mut tokens: Vec<String> = Vec::new();
+    push_tokens(&input, options, &mut tokens);
+    tokens
+}
+
+/// Push the tokens of `input`, which has no markup left, onto `tokens`, with the rest of [`tokenize`].
+fn push_tokens(input: &str, options: &BuildOptions, tokens: &mut Vec<String>) {
+    let lowercased;
+    let input: &str = if options.lowercase {
+        lowercased = input.to_lowercase();
+        &lowercased
+    } else {
+        input
+    };
+
+    match options.level {
+        Level::Word => {
+            for line in input.lines() {
+                for word in line.split_whitespace() {
+                    match options.tokenization {
+                        Tokenization::Whitespace => tokens.push(word.to_string()),
+                        Tokenization::Punctuation => split_punctuation(word, tokens),
+                    }
+                }
+            }
+        }
+        Level::Char => {
+            for word in input.split_whitespace() {
+                if !tokens.is_empty() {
+                    tokens.push(" ".to_string());
+                }
+                tokens.extend(word.graphemes(true).map(String::from));
+            }
+        }
+    }
+}
+
+/// Split `input` into the documents the chain is built from, with [`tokenize`].
+///
+/// That is a single document, one per paragraph with [`BuildOptions::respect_paragraphs`], or one per line with
+/// [`InputMode::Lines`], with [`BuildOptions::stopwords`] and the tokens caught by [`BuildOptions::filters`] removed or split at.
+pub fn tokenize_documents(input: &str, options: &BuildOptions) -> Vec<Vec<String>> {
+    let mut documents = split_documents(input, options);
+    if !options.stopwords.is_empty() {
+        documents = drop_tokens(documents, |token| options.stopwords.contains(&token.to_lowercase()), options.stopword_mode);
+    }
+    if !options.filters.is_empty() && options.level == Level::Word {
+        documents = drop_tokens(documents, |token| options.is_filtered(token), options.filter_mode);
+    }
+    documents
+}
+
+/// Remove the tokens of `documents` that `is_dropped`, or split the documents at them, as `mode` says.
+fn drop_tokens(documents: Vec<Vec<String>>, is_dropped: impl Fn(&String) -> bool, mode: StopwordMode) -> Vec<Vec<String>> {
+    match mode {
+        StopwordMode::Remove => documents.into_iter()
+            .map(|mut tokens| {
+                tokens.retain(|token| !is_dropped(token));
+                tokens
+            })
+            .collect(),
+        StopwordMode::Split => documents.iter()
+            .flat_map(|tokens| tokens.split(&is_dropped))
+            .filter(|tokens| !tokens.is_empty())
+            .map(<[String]>::to_vec)
+            .collect(),
+    }
+}
+
+/// Split `input` into a single document, or one per paragraph or line, with [`tokenize`].
+fn split_documents(input: &str, options: &BuildOptions) -> Vec<Vec<String>> {
+    if !options.respect_paragraphs && options.mode == InputMode::Continuous {
+        return vec![tokenize(input, options)];
+    }
+    // Strip markup first, since it may span several paragraphs
+    let input = &strip_markup(input, options.strip_markup);
+    let options = &BuildOptions { strip_markup: Markup::None, stopwords: HashSet::new(), ..options.clone() };
+    if options.mode == InputMode::Lines {
+        return input.lines()
+            .map(|line| tokenize(line, options))
+            .filter(|tokens| !tokens.is_empty())
+            .collect();
+    }
+
+    let mut documents = Vec::new();
+    let mut paragraph = String::new();
+    for line in input.lines().chain(std::iter::once("")) {
+        if line.trim().is_empty() {
+            if !paragraph.is_empty() {
+                documents.push(tokenize(&paragraph, options));
+                paragraph.clear();
+            }
+        } else {
+            paragraph.push_str(line);
+            paragraph.push('\n');
+        }
+    }
+    documents
+}
+
+/// How the input is divided into the documents a chain is built from, see [`BuildOptions::mode`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum InputMode {
+    /// One continuous stream of text, or one per paragraph with `respect_paragraphs`.
+    #[default]
+    Continuous,
+    /// Every line on its own, for lists of names, lyrics or one sentence per line.
+    Lines,
+}
+
+/// What to do with the [`BuildOptions::stopwords`] in the input, or the tokens caught by [`BuildOptions::filters`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum StopwordMode {
+    /// Remove them, so the tokens on either side become neighbours.
+    #[default]
+    Remove,
+    /// Split the input at them, so no state or transition spans one, as if every stopword ended a document.
+    Split,
+}
+
+/// A kind of word-level token to leave out of the chain, for cleaning up text scraped from the web.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum TokenFilter {
+    /// Anything that looks like a URL: containing `://`, starting with `www.`, or containing both a `/` and a `.`.
+    Urls,
+    /// Numbers: only digits, with `,` or `.` between or after them, like `1887`, `3,000` or `12.5`.
+    Numbers,
+    /// Tokens without a single letter, such as `--`, `***` or `42`. This includes punctuation tokens
+    /// split off with [`Tokenization::Punctuation`].
+    NoLetters,
+}
+
+impl TokenFilter {
+    /// Whether `token` is one this filter leaves out.
+    pub fn matches(self, token: &str) -> bool {
+        match self {
+            TokenFilter::Urls => {
+                // A full stop or closing bracket after a URL isn't part of it
+                let url = token.trim_end_matches(['.', ',', ';', ':', '!', '?', ')', ']', '"', '\'']);
+                url.contains("://") || url.starts_with("www.") || (url.contains('/') && url.contains('.'))
+            }
+            TokenFilter::Numbers => {
+                token.starts_with(|c: char| c.is_ascii_digit()) && token.chars().all(|c| c.is_ascii_digit() || c == ',' || c == '.')
+            }
+            TokenFilter::NoLetters => !token.chars().any(char::is_alphabetic),
+        }
+    }
+}
+
+/// Markup to strip from the input before it's split into tokens.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum Markup {
+    /// Keep the input as it is.
+    #[default]
+    None,
+    /// Drop HTML tags, comments, scripts and styles, and decode the common entities.
+    Html,
+    /// Drop Markdown emphasis, headings, list and quote markers, rules and code blocks, and reduce
+    /// links and images to their text.
+    Markdown,
+}
+
+/// HTML elements that sit inside a run of text, so dropping their tags mustn't split a word in two.
+const INLINE_HTML_TAGS: &[&str] = &[
+    "a", "abbr", "b", "cite", "code", "em", "font", "i", "kbd", "mark", "q", "s", "small", "span",
+    "strong", "sub", "sup", "u",
+];
+
+/// Longest entity name, between `&` and `;`, that [`strip_html`] tries to decode.
+const MAX_ENTITY_LENGTH: usize = 10;
+
+/// `input` with its markup removed.
+fn strip_markup(input: &str, markup: Markup) -> Cow<'_, str> {
+    match markup {
+        Markup::None => Cow::Borrowed(input),
+        Markup::Html => Cow::Owned(strip_html(input)),
+        Markup::Markdown => Cow::Owned(strip_markdown(input)),
+    }
+}
+
+/// `input` without HTML tags and with its entities decoded. Block-level tags become a space.
+fn strip_html(input: &str) -> String {
+    // ASCII lowercasing keeps every byte offset, so both strings can be indexed alike
+    let lower = input.to_ascii_lowercase();
+    let skip_past = |from: usize, closing: &str| lower[from..].find(closing).map_or(input.len(), |end| from + end + closing.len());
+
+    let mut text = String::with_capacity(input.len());
+    let mut position = 0;
+    while let Some(offset) = input[position..].find('<') {
+        let start = position + offset;
+        text.push_str(&input[position..start]);
+        let tag = &lower[start..];
+        if tag.starts_with("<!--") {
+            position = skip_past(start, "-->");
+            continue;
+        }
+        let closing = tag.starts_with("</");
+        let name: String = tag[if closing { 2 } else { 1 }..].chars().take_while(char::is_ascii_alphanumeric).collect();
+        // A `<` that doesn't start a tag, like in `a < b`, is kept
+        let Some(end) = tag.find('>').filter(|_| !name.is_empty() || tag.starts_with("<!")) else {
+            text.push('<');
+            position = start + 1;
+            continue;
+        };
+        position = start + end + 1;
+        if !closing && (name == "script" || name == "style") {
+            // Their contents aren't text, so skip straight past the closing tag
+            position = skip_past(skip_past(position, &format!("</{}", name)), ">");
+        }
+        if !INLINE_HTML_TAGS.contains(&name.as_str()) {
+            text.push(' ');
+        }
+    }
+    text.push_str(&input[position..]);
+    decode_html_entities(&text)
+}
+
+/// `text` with named entities like `&amp;` and numeric ones like `&#8217;` replaced by their characters.
+/// Anything else starting with `&` is kept as it is.
+fn decode_html_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest[1..].find(';')
+            .filter(|&end| end <= MAX_ENTITY_LENGTH)
+            .and_then(|end| decode_html_entity(&rest[1..end + 1]).map(|c| (c, end + 2)));
+        match entity {
+            Some((c, length)) => {
+                decoded.push(c);
+                rest = &rest[length..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// The character an entity name (without `&` and `;`) stands for.
+fn decode_html_entity(name: &str) -> Option<char> {
+    if let Some(number) = name.strip_prefix('#') {
+        let code = match number.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => number.parse().ok()?,
+        };
+        return char::from_u32(code);
+    }
+    Some(match name {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => ' ',
+        "ndash" => '\u{2013}',
+        "mdash" => '\u{2014}',
+        "lsquo" => '\u{2018}',
+        "rsquo" => '\u{2019}',
+        "ldquo" => '\u{201C}',
+        "rdquo" => '\u{201D}',
+        "hellip" => '\u{2026}',
+        _ => return None,
+    })
+}
+
+/// `input` without Markdown formatting. Every line is kept, so paragraphs stay separated by blank lines.
+fn strip_markdown(input: &str) -> String {
+    let mut text = String::with_capacity(input.len());
+    let mut in_code_block = false;
+    for line in input.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_code_block = !in_code_block;
+        } else if !in_code_block && !is_markdown_rule(trimmed) && !is_markdown_reference(trimmed) {
+            text.push_str(&strip_inline_markdown(strip_markdown_line_markers(trimmed)));
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// Whether `line` is a horizontal rule like `---` or `* * *`, or the `===` underlining a heading.
+fn is_markdown_rule(line: &str) -> bool {
+    let markers: Vec<char> = line.chars().filter(|c| !c.is_whitespace()).collect();
+    markers.len() >= 3 && ['-', '*', '_', '='].iter().any(|&marker| markers.iter().all(|&c| c == marker))
+}
+
+/// Whether `line` defines the target of a reference link, like `[1]: https://example.com`.
+fn is_markdown_reference(line: &str) -> bool {
+    line.starts_with('[') && line.find("]:").is_some_and(|end| end > 1)
+}
+
+/// `line` without leading quote, heading and list markers.
+fn strip_markdown_line_markers(mut line: &str) -> &str {
+    loop {
+        let stripped = if let Some(rest) = line.strip_prefix('>') {
+            rest
+        } else if let Some(rest) = line.strip_prefix(['-', '*', '+']).filter(|rest| rest.starts_with(' ')) {
+            rest
+        } else if line.starts_with('#') {
+            let rest = line.trim_start_matches('#');
+            if !rest.is_empty() && !rest.starts_with(' ') {
+                return line;
+            }
+            rest
+        } else {
+            let digits = line.len() - line.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+            match line[digits..].strip_prefix(['.', ')']) {
+                Some(rest) if digits > 0 && rest.starts_with(' ') => rest,
+                _ => return line,
+            }
+        };
+        line = stripped.trim_start();
+    }
+}
+
+/// `line` without emphasis, code and escape markers, and with links and images reduced to their text.
+fn strip_inline_markdown(line: &str) -> String {
+    let chars: Vec<char> = line.chars().collect();
+    let mut text = String::with_capacity(line.len());
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '\\' if chars.get(i + 1).is_some_and(char::is_ascii_punctuation) => {
+                text.push(chars[i + 1]);
+                i += 2;
+            }
+            '!' if chars.get(i + 1) == Some(&'[') && markdown_link_end(&chars, i + 1).is_some() => i += 1,
+            '[' => match markdown_link_end(&chars, i) {
+                Some((label_end, end)) => {
+                    text.push_str(&strip_inline_markdown(&chars[i + 1..label_end].iter().collect::<String>()));
+                    i = end;
+                }
+                None => {
+                    text.push(c);
+                    i += 1;
+                }
+            },
+            '`' => i += 1,
+            '*' | '_' | '~' => {
+                let run = chars[i..].iter().take_while(|&&other| other == c).count();
+                let before = i.checked_sub(1).map(|j| chars[j]);
+                let after = chars.get(i + run).copied();
+                let spaced = |c: Option<char>| c.is_none_or(char::is_whitespace);
+                let in_word = |c: Option<char>| c.is_some_and(char::is_alphanumeric);
+                let marker = !(spaced(before) && spaced(after)) && match c {
+                    // Underscores inside a word, like in snake_case, aren't emphasis
+                    '_' => !(in_word(before) && in_word(after)),
+                    '~' => run >= 2,
+                    _ => true,
+                };
+                if !marker {
+                    text.extend(&chars[i..i + run]);
+                }
+                i += run;
+            }
+            _ => {
+                text.push(c);
+                i += 1;
+            }
+        }
+    }
+    text
+}
+
+/// For a link starting with the `[` at `start`, like `[text](url)` or `[text][ref]`, the index of the `]`
+/// closing its text and the index just past the whole link.
+fn markdown_link_end(chars: &[char], start: usize) -> Option<(usize, usize)> {
+    let closing = |open: char, close: char, from: usize| {
+        let mut depth = 0;
+        for (j, &c) in chars.iter().enumerate().skip(from) {
+            if c == open {
+                depth += 1;
+            } else if c == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(j);
+                }
+            }
+        }
+        None
+    };
+    let label_end = closing('[', ']', start)?;
+    let end = match chars.get(label_end + 1) {
+        Some('(') => closing('(', ')', label_end + 1)?,
+        Some('[') => closing('[', ']', label_end + 1)?,
+        _ => return None,
+    };
+    Some((label_end, end + 1))
+}
+
+/// Push `word` onto `tokens`, with every punctuation character as a token of its own except the ones that join two
+/// letters or digits into a single word: apostrophes (`don't`, `it’s`), hyphens (`mother-in-law`), full stops
+/// (`e.g`, `3.14`) and commas between digits (`1,000`).
+///
+/// So `don't,` gives `don't` and `,`, `mother-in-law.` gives `mother-in-law` and `.`, and `'quoted'` gives `'`,
+/// `quoted` and `'`.
+fn split_punctuation(word: &str, tokens: &mut Vec<String>) {
+    let chars: Vec<char> = word.chars().collect();
+    let joins = |i: usize| {
+        let (Some(&before), Some(&after)) = (i.checked_sub(1).and_then(|j| chars.get(j)), chars.get(i + 1)) else {
+            return false;
+        };
+        before.is_alphanumeric() && after.is_alphanumeric() && match chars[i] {
+            '\'' | '\u{2019}' | '-' | '.' => true,
+            ',' => before.is_numeric() && after.is_numeric(),
+            _ => false,
+        }
+    };
+
+    let mut current = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_alphanumeric() || joins(i) {
+            current.push(c);
+        } else {
+            if !current.is_empty() {
+                tokens.push(std::mem::take(&mut current));
+            }
+            tokens.push(c.to_string());
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+}
+
+/// Whether a punctuation token attaches to the token before it, e.g. `,` or `)`.
+fn closes(token: &str) -> bool {
+    token.chars().count() == 1
+        && token.chars().all(|c| ".,;:!?)]}\u{201D}\u{2019}\u{2026}%".contains(c))
+}
+
+/// Whether a punctuation token attaches to the token after it, e.g. `(`.
+fn opens(token: &str) -> bool {
+    token.chars().count() == 1 && token.chars().all(|c| "([{\u{201C}\u{2018}".contains(c))
+}
+
+/// Join generated `tokens` back into text with `separator` between them, the inverse of [`tokenize`]
+/// when `separator` is the level's own [`Level::separator`].
+///
+/// With [`Tokenization::Punctuation`] at word level, closing punctuation is attached to the token before it and opening
+/// punctuation to the token after it. A straight quote closes when it's followed by punctuation, ends the
+/// text, or sits between punctuation and a lowercase word (`," she`), opens when it follows opening
+/// punctuation or another quote, and otherwise alternates.
+pub fn join_tokens(tokens: &[String], level: Level, tokenization: Tokenization, separator: &str) -> String {
+    if level == Level::Char || tokenization == Tokenization::Whitespace {
+        return tokens.join(separator);
+    }
+
+    let is_quote = |token: &str| token == "\"" || token == "'";
+    let mut output = String::new();
+    let mut attach_next = true;
+    let mut open_quotes = [false; 2];
+    for (i, token) in tokens.iter().enumerate() {
+        let (attach_prev, attach_after) = if is_quote(token) {
+            let open = &mut open_quotes[usize::from(token == "'")];
+            let next = tokens.get(i + 1);
+            let prev = i.checked_sub(1).map(|j| &tokens[j]);
+            let starts_lowercase = next.and_then(|next| next.chars().next()).is_some_and(char::is_lowercase);
+            *open = if next.is_none_or(|next| closes(next) || is_quote(next))
+                || (starts_lowercase && prev.is_some_and(|prev| closes(prev))) {
+                false
+            } else if prev.is_none_or(|prev| opens(prev) || is_quote(prev)) {
+                true
+            } else {
+                !*open
+            };
+            (!*open, *open)
+        } else {
+            (closes(token), opens(token))
+        };
+        if !attach_next && !attach_prev {
+            output.push_str(separator);
+        }
+        output.push_str(token);
+        attach_next = attach_after;
+    }
+    output
+}
+
+/// Split a state key back into its tokens.
+pub fn split_state(state: &str, level: Level) -> Vec<String> {
+    match level {
+        // The only state of an order 0 model has no tokens at all
+        Level::Word if state.is_empty() => Vec::new(),
+        Level::Word => state.split(' ').map(String::from).collect(),
+        Level::Char => state.graphemes(true).map(String::from).collect(),
+    }
+}
+
+/// Options controlling how a chain is built by [`generate_markov_chain`].
+#[derive(Clone, Debug, Default)]
+pub struct BuildOptions {
+    /// What a single token of the chain is.
+    pub level: Level,
+    /// How word-level input is split into tokens.
+    pub tokenization: Tokenization,
+    /// Markup to remove from the input before tokenizing it.
+    pub strip_markup: Markup,
+    /// Lowercase tokens to leave out of the chain, compared case-insensitively. Only applied by
+    /// [`tokenize_documents`], according to `stopword_mode`.
+    pub stopwords: HashSet<String>,
+    /// What to do with the `stopwords` in the input.
+    pub stopword_mode: StopwordMode,
+    /// Kinds of tokens to leave out of word-level chains, according to `filter_mode`. Ignored at [`Level::Char`].
+    pub filters: Vec<TokenFilter>,
+    /// What to do with the tokens caught by `filters`.
+    pub filter_mode: StopwordMode,
+    /// Lowercase the input, so differently capitalized words share states.
+    ///
+    /// Generation from a lowercased model starts from states that followed the end of a sentence,
+    /// since there are no capitals to look for.
+    pub lowercase: bool,
+    /// Put `state_size` [`Level::start_token`]s before every document and a [`Level::end_token`] after it.
+    ///
+    /// Generation then always begins where a document began, and stops where one ended.
+    pub sentinels: bool,
+    /// Build the chain over every document backwards, so a state's successors are the tokens that came before it.
+    ///
+    /// Generation from such a model goes backwards from the prompt, for text leading up to it, and reverses
+    /// the result again. Forward and backward models can't be merged.
+    pub reverse: bool,
+    /// Treat every blank-line separated paragraph as a document of its own, so no state spans two paragraphs.
+    pub respect_paragraphs: bool,
+    /// Whether the input is a continuous text or a document per line.
+    ///
+    /// Lines are usually combined with `sentinels`, so every generated text starts and ends like one of them did.
+    pub mode: InputMode,
+    /// Build on this many threads (0 for one per core) instead of sequentially.
+    pub threads: Option<usize>,
+    /// Print every recorded transition to stderr.
+    pub verbose: bool,
+}
+
+impl BuildOptions {
+    /// Whether one of [`BuildOptions::filters`] leaves `token` out.
+    fn is_filtered(&self, token: &str) -> bool {
+        self.level == Level::Word && self.filters.iter().any(|filter| filter.matches(token))
+    }
+}
+
+/// Characters that end a sentence unless configured otherwise.
+pub const DEFAULT_SENTENCE_TERMINATORS: &str = ".!?";
+
+/// Longest repeating block of tokens, in tokens, that `max_repeat` looks for.
+pub const MAX_LOOP_LENGTH: usize = 50;
+
+/// Average length of a sentence, in tokens, that `sentences` may grow to before generation gives up.
+pub const MAX_SENTENCE_LENGTH: usize = 200;
+
+/// How many times `min_words` may restart generation from a fresh starter after a dead-end.
+pub const MAX_RESTARTS: usize = 10;
+
+/// How many tokens past `max_words` generation may run while looking for the end of a sentence.
+pub const MAX_SENTENCE_OVERRUN: usize = 100;
+
+/// Options controlling how text is sampled by [`generate_text`].
+#[derive(Clone, Debug)]
+pub struct GenerateOptions {
+    /// Maximum number of tokens to generate, counting the starter. A prompt is never cut short, but counts too.
+    pub max_words: usize,
+    /// Once `max_words` is reached, keep going until a token ends a sentence
+    /// (for at most [`MAX_SENTENCE_OVERRUN`] more tokens).
+    pub end_at_sentence: bool,
+    /// Generate this many sentences instead of `max_words` tokens (for at most
+    /// [`MAX_SENTENCE_LENGTH`] tokens per sentence).
+    pub sentences: Option<usize>,
+    /// Stop before the first token that would make the text longer than this many characters (Unicode
+    /// scalar values, not bytes), instead of after `max_words` tokens.
+    pub max_chars: Option<usize>,
+    /// Characters that end a sentence, see [`ends_sentence`].
+    pub sentence_terminators: String,
+    /// Sampling temperature: each successor is weighted by `count^(1 / temperature)`.
+    ///
+    /// `1.0` samples proportionally to the observed counts, lower values favour the most frequent
+    /// successors, higher values flatten towards uniform, and `0.0` always picks the most frequent one.
+    pub temperature: f64,
+    /// Also weight each successor by its length in characters raised to this power, on top of `temperature`:
+    /// positive values favour longer tokens, negative ones shorter tokens, and `0.0` leaves the weights alone.
+    /// At a `temperature` of `0.0` it only breaks ties between the most frequent successors.
+    pub length_bias: f64,
+    /// Only sample from the `top_k` most frequent successors of each state, ties going to the
+    /// alphabetically first word.
+    pub top_k: Option<usize>,
+    /// Only sample from the most frequent successors of each state whose combined probability first
+    /// exceeds `top_p`. Can't be combined with `top_k`.
+    pub top_p: Option<f64>,
+    /// Emit this text first and continue generating from its last `state_size` tokens,
+    /// instead of starting from a random state.
+    pub prompt: Option<String>,
+    /// What to do when the end of the prompt isn't a state in the model.
+    pub prompt_fallback: PromptFallback,
+    /// Leave the prompt out of the generated text, so it only holds the continuation. The prompt then doesn't
+    /// count towards `max_words`, `min_words`, `sentences` or `max_chars` either.
+    pub omit_prompt: bool,
+    /// Which states generation may start from when there's no prompt, see [`get_text_starter_with`].
+    pub start_strategy: StartStrategy,
+    /// Pick among the states allowed by `start_strategy` in proportion to how often each started a sentence
+    /// in the training input, instead of uniformly.
+    pub weighted_start: bool,
+    /// On a dead-end, retry with the state's last `state_size - 1` tokens, and so on down to a
+    /// single token, before giving up.
+    pub backoff: bool,
+    /// Sample from a mix of the model's own order and every lower one, instead of from its own order
+    /// alone: one weight for each order from 1 to the state size, lowest first. Each order's
+    /// probabilities are read from states sharing the current state's ending, as with `backoff`,
+    /// and orders whose ending was never seen are left out.
+    pub interpolate: Option<Vec<f64>>,
+    /// When the same block of up to [`MAX_LOOP_LENGTH`] tokens has been emitted more than `max_repeat`
+    /// times in a row, drop the extra repetition and jump to a fresh random starter.
+    pub max_repeat: Option<usize>,
+    /// When generation hits a dead-end before this many tokens, append text from a fresh random starter
+    /// instead of stopping, up to [`MAX_RESTARTS`] times.
+    pub min_words: Option<usize>,
+    /// When generation hits a dead-end before `max_words`, append text from a fresh random starter instead of
+    /// stopping, up to this many times. Restarts for `min_words` count towards it, but may go on up to
+    /// [`MAX_RESTARTS`] times regardless.
+    pub retries: usize,
+    /// Put this between the generated tokens instead of the level's own [`Level::separator`].
+    pub separator: Option<String>,
+    /// Uppercase the first letter of the generated text, after any leading punctuation. The tokens
+    /// themselves are left alone, so this only changes the returned text.
+    pub capitalize_start: bool,
+    /// Remove opening quotes and brackets that are never closed, and punctuation left dangling at the end, from
+    /// the returned text, see [`trim_output`].
+    pub trim_output: bool,
+    /// Drop or lowercase capitalized words that don't start a sentence, as a best-effort way to leave names out
+    /// of the returned text, see [`strip_proper_nouns`].
+    pub strip_proper_nouns: Option<ProperNounFilter>,
+    /// Record every sampled token in [`GeneratedText::trace`], with the state it was sampled from and how likely it was.
+    pub trace: bool,
+    /// Print every generation step to stderr.
+    pub verbose: bool,
+    /// When the end of the prompt isn't a state of the model, look for one that only differs in case, before
+    /// falling back to `prompt_fallback`, and do the same for a state generation reaches that has no successors,
+    /// before backing off or ending. This doesn't change the text as it's emitted, only how states are matched.
+    pub case_insensitive_lookup: bool,
+    /// Stop generating as soon as this is set, e.g. from a Ctrl-C handler, and return the text generated so far
+    /// with [`GeneratedText::interrupted`] set.
+    pub interrupt: Option<Arc<AtomicBool>>,
+    /// Keep the text from copying more than [`VerbatimIndex::max_verbatim`] consecutive tokens of the training
+    /// input: successors that would are left out, and a state left with none is a dead-end.
+    pub verbatim: Option<Arc<VerbatimIndex>>,
+}
+
+impl Default for GenerateOptions {
+    fn default() -> Self {
+        GenerateOptions {
+            max_words: 100,
+            end_at_sentence: false,
+            sentences: None,
+            max_chars: None,
+            sentence_terminators: DEFAULT_SENTENCE_TERMINATORS.to_string(),
+            temperature: 1.0,
+            length_bias: 0.0,
+            top_k: None,
+            top_p: None,
+            prompt: None,
+            prompt_fallback: PromptFallback::Error,
+            omit_prompt: false,
+            start_strategy: StartStrategy::Capital,
+            weighted_start: false,
+            backoff: false,
+            interpolate: None,
+            max_repeat: None,
+            min_words: None,
+            retries: 0,
+            separator: None,
+            capitalize_start: false,
+            trim_output: false,
+            strip_proper_nouns: None,
+            trace: false,
+            verbose: false,
+            case_insensitive_lookup: false,
+            interrupt: None,
+            verbatim: None,
+        }
+    }
+}
+
+impl GenerateOptions {
+    /// Whether [`GenerateOptions::interrupt`] has been set.
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupt.as_ref().is_some_and(|interrupt| interrupt.load(atomic::Ordering::Relaxed))
+    }
+
+    /// What to put between generated tokens at `level`.
+    fn separator(&self, level: Level) -> &str {
+        self.separator.as_deref().unwrap_or(level.separator())
+    }
+
+    fn validate(&self) -> Result<(), MarkovError> {
+        if self.max_words == 0 {
+            return Err(MarkovError::InvalidOption("Max words must be greater than 0".to_string()));
+        }
+        if !self.temperature.is_finite() || self.temperature < 0.0 {
+            return Err(MarkovError::InvalidOption("Temperature must be a non-negative number".to_string()));
+        }
+        if !self.length_bias.is_finite() {
+            return Err(MarkovError::InvalidOption("Length bias must be a finite number".to_string()));
+        }
+        if self.top_k == Some(0) {
+            return Err(MarkovError::InvalidOption("Top-k must be greater than 0".to_string()));
+        }
+        if let Some(top_p) = self.top_p
+            && !(top_p > 0.0 && top_p <= 1.0) {
+            return Err(MarkovError::InvalidOption("Top-p must be greater than 0 and at most 1".to_string()));
+        }
+        if self.top_k.is_some() && self.top_p.is_some() {
+            return Err(MarkovError::InvalidOption("Top-k and top-p sampling can't be used together".to_string()));
+        }
+        if self.max_chars == Some(0) {
+            return Err(MarkovError::InvalidOption("Max chars must be greater than 0".to_string()));
+        }
+        if self.sentences == Some(0) {
+            return Err(MarkovError::InvalidOption("Sentences must be greater than 0".to_string()));
+        }
+        if let Some(weights) = &self.interpolate {
+            if let Some(weight) = weights.iter().find(|weight| !weight.is_finite() || **weight < 0.0) {
+                return Err(MarkovError::InvalidOption(format!("Interpolation weights must be non-negative numbers, got {}", weight)));
+            }
+            if weights.iter().sum::<f64>() == 0.0 {
+                return Err(MarkovError::InvalidOption("Interpolation weights can't all be 0".to_string()));
+            }
+        }
+        if self.max_repeat == Some(0) {
+            return Err(MarkovError::InvalidOption("Max repeat must be greater than 0".to_string()));
+        }
+        if let Some(min_words) = self.min_words
+            && min_words > self.max_words {
+            return Err(MarkovError::InvalidOption(format!("Min words ({}) can't be more than max words ({})", min_words, self.max_words)));
+        }
+        Ok(())
+    }
+}
+
+/// What to do when generation is prompted with text whose final state isn't in the model.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum PromptFallback {
+    /// Fail with an error.
+    #[default]
+    Error,
+    /// Continue from a state ending with as many of the prompt's final tokens as possible,
+    /// or from a random starter if there is none.
+    Nearest,
+}
+
+/// What [`strip_proper_nouns`] does with a word it takes for a proper noun.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ProperNounFilter {
+    /// Leave the word out.
+    Drop,
+    /// Lowercase the whole word.
+    Lowercase,
+}
+
+/// Every run of tokens of some training documents one token longer than generated text may copy, see
+/// [`GenerateOptions::verbatim`].
+///
+/// Runs are only kept as hashes, so a rare collision may leave out a successor that wouldn't have copied anything.
+#[derive(Clone, Debug)]
+pub struct VerbatimIndex {
+    max_verbatim: usize,
+    runs: FxHashSet<u64>,
+}
+
+impl VerbatimIndex {
+    /// Index `documents`, tokenized the way the model was built, for text that copies at most `max_verbatim`
+    /// consecutive tokens of them. That has to be more than the state size, since every transition of the model
+    /// copies a state and its successor.
+    pub fn new(documents: &[Vec<String>], max_verbatim: usize) -> Self {
+        let runs = documents.iter()
+            .flat_map(|tokens| tokens.windows(max_verbatim + 1))
+            .map(|run| FxBuildHasher.hash_one(run))
+            .collect();
+        VerbatimIndex { max_verbatim, runs }
+    }
+
+    /// The most consecutive tokens of the training input generated text may copy.
+    pub fn max_verbatim(&self) -> usize {
+        self.max_verbatim
+    }
+
+    /// Whether the last `max_verbatim` tokens of `recent` followed by `next` occur in the training input.
+    fn copies(&self, recent: &VecDeque<String>, next: &str, reversed: bool) -> bool {
+        if recent.len() < self.max_verbatim {
+            return false;
+        }
+        let mut run: Vec<&str> = recent.iter().skip(recent.len() - self.max_verbatim).map(String::as_str).collect();
+        run.push(next);
+        // A backward model generates the text from its end
+        if reversed {
+            run.reverse();
+        }
+        self.runs.contains(&FxBuildHasher.hash_one(&run[..]))
+    }
+}
+
+/// Which states generation starts from when it isn't prompted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum StartStrategy {
+    /// States starting with a capital letter, preferably not followed by another capitalized word.
+    /// Lowercased models use the states that followed the end of a sentence instead.
+    #[default]
+    Capital,
+    /// Only states that directly followed a token ending a sentence, or the start of a document
+    /// with sentinels. Falls back to `capital` if there are none.
+    Sentence,
+    /// Any state of the model.
+    Random,
+    /// The states of `capital`, each picked in proportion to its number of distinct successors.
+    Branchy,
+}
+
+/// Whether `token` ends a sentence, i.e. ends with one of `terminators`.
+///
+/// Closing quotes and brackets after the terminator are ignored, so `done."` ends a sentence too.
+pub fn ends_sentence(token: &str, terminators: &str) -> bool {
+    token.trim_end_matches(['"', '\'', ')', ']', '}', '\u{201D}', '\u{2019}'])
+        .chars()
+        .next_back()
+        .is_some_and(|c| terminators.contains(c))
+}
+
+/// Build a Markov chain from `input`, keyed by states of `state_size` tokens.
+///
+/// A `state_size` of 0 builds an order 0 (unigram) chain: a single empty state whose successors are
+/// all tokens of the input, so each generated token is sampled independently of the ones before it.
+pub fn generate_markov_chain(input: &str, state_size: usize, options: &BuildOptions) -> Result<Chain, MarkovError> {
+    // Collect Words
+    let documents = tokenize_documents(input, options);
+    build_chain(&documents, state_size, options)
+}
+
+/// Build a single Markov chain from several already tokenized documents.
+///
+/// Each document is treated separately, so no state ever spans the end of one document and the start of the next.
+pub fn build_chain(documents: &[Vec<String>], state_size: usize, options: &BuildOptions) -> Result<Chain, MarkovError> {
+    Ok(CompactModel::build(documents, state_size, options)?.into_model().chain)
+}
+
+/// Every distinct token of a [`CompactModel`], stored once and referred to by its index.
+#[derive(Clone, Debug, Default)]
+struct Interner {
+    tokens: Vec<Box<str>>,
+    ids: FxHashMap<Box<str>, u32>,
+}
+
+impl Interner {
+    fn intern(&mut self, token: &str) -> u32 {
+        if let Some(&id) = self.ids.get(token) {
+            return id;
+        }
+        let id = u32::try_from(self.tokens.len()).expect("more than u32::MAX distinct tokens");
+        self.tokens.push(token.into());
+        self.ids.insert(token.into(), id);
+        id
+    }
+
+    fn token(&self, id: u32) -> &str {
+        &self.tokens[id as usize]
+    }
+
+    /// The [`Chain`] key of an interned state.
+    fn join(&self, state: &[u32], level: Level) -> String {
+        state.iter().map(|&id| self.token(id)).collect::<Vec<_>>().join(level.separator())
+    }
+}
+
+/// The interned tokens of a document, backwards if `reverse` is set. They're interned in their original order
+/// either way, just like [`ModelBuilder`] does.
+fn interned(tokens: &mut Interner, document: &[String], reverse: bool) -> Vec<u32> {
+    let mut ids: Vec<u32> = document.iter().map(|token| tokens.intern(token)).collect();
+    if reverse {
+        ids.reverse();
+    }
+    ids
+}
+
+/// Interned states mapped to their interned successors and how often each was seen.
+///
+/// Building hashes every state of the input, and SipHash's resistance to collision attacks buys nothing for
+/// a model's own tokens, so this uses the much faster FxHash. A [`Chain`] keeps the standard hasher.
+type InternedStates = FxHashMap<Box<[u32]>, Vec<(u32, u32)>>;
+
+/// A model whose tokens are interned, so each distinct token is stored once instead of once for every
+/// state and successor it appears in.
+///
+/// Models are built as one of these, which takes far less memory than a [`Chain`] of strings.
+/// [`CompactModel::into_model`] converts it for generating text, while [`CompactModel::save_as`] writes
+/// the same file a [`MarkovModel`] would without converting it first.
+#[derive(Clone, Debug)]
+pub struct CompactModel {
+    tokens: Interner,
+    states: InternedStates,
+    state_size: usize,
+    level: Level,
+    tokenization: Tokenization,
+    lowercase: bool,
+    sentinels: bool,
+    reversed: bool,
+    strip_markup: Markup,
+    mode: InputMode,
+    respect_paragraphs: bool,
+    stopwords: Vec<String>,
+    stopword_mode: StopwordMode,
+    filters: Vec<TokenFilter>,
+    filter_mode: StopwordMode,
+}
+
+/// The stopwords of `options` in order, so a model file doesn't depend on the order of a [`HashSet`].
+fn sorted_stopwords(options: &BuildOptions) -> Vec<String> {
+    let mut stopwords: Vec<String> = options.stopwords.iter().cloned().collect();
+    stopwords.sort();
+    stopwords
+}
+
+/// Fraction of states with a single successor above which [`CompactModel::is_undertrained`] considers a model
+/// too sparse for its state size.
+pub const UNDERTRAINED_DEAD_ENDS: f64 = 0.9;
+
+impl CompactModel {
+    /// Build a model from several already tokenized documents, see [`build_chain`].
+    pub fn build(documents: &[Vec<String>], state_size: usize, options: &BuildOptions) -> Result<Self, MarkovError> {
+        Self::build_weighted(documents, &vec![1; documents.len()], state_size, options)
+    }
+
+    /// Like [`CompactModel::build`], but count every transition of `documents[i]` `weights[i]` times, e.g. to make
+    /// the style of one source count for more than the others.
+    pub fn build_weighted(documents: &[Vec<String>], weights: &[u32], state_size: usize, options: &BuildOptions) -> Result<Self, MarkovError> {
+        if weights.len() != documents.len() {
+            return Err(MarkovError::InvalidOption(format!("Got {} weights for {} documents", weights.len(), documents.len())));
+        }
+        if weights.contains(&0) {
+            return Err(MarkovError::InvalidOption("Document weights must be at least 1".to_string()));
+        }
+        let level = options.level;
+        let total: usize = documents.iter().map(Vec::len).sum();
+        if total == 0 {
+            return Err(MarkovError::EmptyInput);
+        }
+        let mut tokens = Interner::default();
+        let (documents, weights): (Vec<Vec<u32>>, Vec<u32>) = if options.sentinels {
+            let start = tokens.intern(level.start_token());
+            let end = tokens.intern(level.end_token());
+            documents.iter()
+                .zip(weights)
+                .filter(|(input_vec, _)| !input_vec.is_empty())
+                .map(|(input_vec, &weight)| {
+                    let mut ids = vec![start; state_size];
+                    ids.extend(interned(&mut tokens, input_vec, options.reverse));
+                    ids.push(end);
+                    (ids, weight)
+                })
+                .unzip()
+        } else {
+            let documents = documents.iter()
+                .map(|input_vec| interned(&mut tokens, input_vec, options.reverse))
+                .collect();
+            (documents, weights.to_vec())
+        };
+        let longest = documents.iter().map(Vec::len).max().unwrap_or(0);
+        if longest < state_size + 1 {
+            return Err(MarkovError::InsufficientWords { found: longest, needed: state_size + 1, state_size, level });
+        }
+
+        if options.verbose {
+            eprintln!("Collected {} {} from input.", total, level.unit());
+        }
+
+        let states = match options.threads {
+            Some(threads) => build_states_parallel(&documents, &weights, state_size, &tokens, options, threads)?,
+            None => {
+                // There are at most as many states as transitions, so the map never has to grow
+                let transitions = documents.iter().map(|input_vec| input_vec.len().saturating_sub(state_size)).sum();
+                let mut states = InternedStates::with_capacity_and_hasher(transitions, FxBuildHasher);
+                for (input_vec, &weight) in documents.iter().zip(&weights) {
+                    add_transitions(&mut states, input_vec, state_size..input_vec.len(), state_size, weight, &tokens, options);
+                }
+                states
+            }
+        };
+
+        if options.verbose {
+            eprintln!("Markov chain construction complete. States: {}", states.len());
+        }
+        Ok(CompactModel {
+            tokens,
+            states,
+            state_size,
+            level,
+            tokenization: options.tokenization,
+            lowercase: options.lowercase,
+            sentinels: options.sentinels,
+            reversed: options.reverse,
+            strip_markup: options.strip_markup,
+            mode: options.mode,
+            respect_paragraphs: options.respect_paragraphs,
+            stopwords: sorted_stopwords(options),
+            stopword_mode: options.stopword_mode,
+            filters: options.filters.clone(),
+            filter_mode: options.filter_mode,
+        })
+    }
+
+    /// Number of states.
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    /// Whether the model has no states at all.
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    /// Number of tokens in each state.
+    pub fn state_size(&self) -> usize {
+        self.state_size
+    }
+
+    /// Number of distinct tokens in the input the model was built from, not counting sentinels.
+    pub fn vocabulary(&self) -> usize {
+        self.tokens.tokens.iter().filter(|token| !self.level.is_sentinel(token)).count()
+    }
+
+    /// Total number of recorded transitions, i.e. the sum of all successor counts.
+    pub fn transitions(&self) -> u64 {
+        self.states.values().flatten().map(|&(_, count)| count as u64).sum()
+    }
+
+    /// Number of states with only a single successor, which generation can only follow one way.
+    pub fn dead_ends(&self) -> usize {
+        self.states.values().filter(|successors| successors.len() == 1).count()
+    }
+
+    /// Fraction of the states that have only a single successor, see [`CompactModel::dead_ends`].
+    pub fn dead_end_fraction(&self) -> f64 {
+        if self.states.is_empty() { 0.0 } else { self.dead_ends() as f64 / self.len() as f64 }
+    }
+
+    /// Whether more than [`UNDERTRAINED_DEAD_ENDS`] of the states have a single successor, so generation can
+    /// hardly do anything but repeat the input word for word. A smaller state size or more input helps.
+    pub fn is_undertrained(&self) -> bool {
+        self.dead_end_fraction() > UNDERTRAINED_DEAD_ENDS
+    }
+
+    /// Drop every successor seen fewer than `min_count` times, and every state left without successors,
+    /// see [`prune_chain`].
+    pub fn prune(&mut self, min_count: u32) -> Pruned {
+        prune_states(&mut self.states, min_count)
+    }
+
+    /// Keep only the `max_successors` most frequent successors of every state, see [`cap_successors`].
+    pub fn cap_successors(&mut self, max_successors: usize) -> Pruned {
+        cap_states(&mut self.states, max_successors)
+    }
+
+    /// The model's transitions as a plain [`Chain`].
+    pub fn to_chain(&self) -> Chain {
+        self.states.iter()
+            .map(|(state, successors)| self.chain_entry(state, successors))
+            .collect()
+    }
+
+    /// Convert into a [`MarkovModel`] to generate text from, freeing each interned state as it goes.
+    pub fn into_model(mut self) -> MarkovModel {
+        let states = std::mem::take(&mut self.states);
+        let mut chain = Chain::with_capacity(states.len());
+        for (state, successors) in states {
+            let (key, successors) = self.chain_entry(&state, &successors);
+            chain.insert(key, successors);
+        }
+        MarkovModel {
+            chain,
+            state_size: self.state_size,
+            level: self.level,
+            tokenization: self.tokenization,
+            lowercase: self.lowercase,
+            sentinels: self.sentinels,
+            reversed: self.reversed,
+            strip_markup: self.strip_markup,
+            mode: self.mode,
+            respect_paragraphs: self.respect_paragraphs,
+            stopwords: self.stopwords,
+            stopword_mode: self.stopword_mode,
+            filters: self.filters,
+            filter_mode: self.filter_mode,
+            legacy_options: false,
+            starters: OnceLock::new(),
+            lowercase_states: OnceLock::new(),
+        }
+    }
+
+    fn chain_entry(&self, state: &[u32], successors: &[(u32, u32)]) -> (String, Vec<(String, u32)>) {
+        let successors = successors.iter().map(|&(id, count)| (self.tokens.token(id).to_string(), count)).collect();
+        (self.tokens.join(state, self.level), successors)
+    }
+
+    fn file(&self) -> ModelFile<'_> {
+        ModelFile {
+            version: MODEL_FORMAT_VERSION,
+            state_size: self.state_size,
+            level: self.level,
+            tokenization: self.tokenization,
+            lowercase: self.lowercase,
+            sentinels: self.sentinels,
+            reversed: self.reversed,
+            strip_markup: self.strip_markup,
+            mode: self.mode,
+            respect_paragraphs: self.respect_paragraphs,
+            stopwords: Cow::Borrowed(&self.stopwords),
+            stopword_mode: self.stopword_mode,
+            filters: Cow::Borrowed(&self.filters),
+            filter_mode: self.filter_mode,
+            states: States::Compact(self),
+        }
+    }
+
+    /// Serialize the model in the given file `format`, exactly as [`model_to_bytes`] would after converting it.
+    pub fn to_bytes(&self, format: ModelFormat) -> Result<Vec<u8>, MarkovError> {
+        file_to_bytes(&self.file(), format)
+    }
+
+    /// Write the model to `path`, see [`MarkovModel::save_as`].
+    #[cfg(feature = "fs")]
+    pub fn save_as(&self, path: impl AsRef<Path>, format: ModelFormat, compress: bool) -> Result<(), MarkovError> {
+        write_model_file(path.as_ref(), self.to_bytes(format)?, compress)
+    }
+}
+
+/// Builds a [`CompactModel`] from text fed to it a line at a time, so the input never has to be in memory
+/// all at once: only the distinct tokens, the states and the last `state_size` tokens are kept.
+///
+/// Gives the same model as [`CompactModel::build`] on the [`tokenize_documents`] of the whole text, as long
+/// as [`BuildOptions::strip_markup`] is [`Markup::None`], since markup can span lines. [`BuildOptions::threads`]
+/// is ignored. With [`BuildOptions::reverse`], a whole document is kept until it ends, to be added backwards.
+#[derive(Clone, Debug)]
+pub struct ModelBuilder {
+    model: CompactModel,
+    options: BuildOptions,
+    /// The last `state_size` tokens of the current document.
+    window: Vec<u32>,
+    /// Number of tokens in the current document so far, including start sentinels. 0 if none was added yet.
+    document_len: usize,
+    /// Number of tokens in the longest document so far, including sentinels.
+    longest: usize,
+    /// Number of tokens added so far, not counting sentinels.
+    total: usize,
+    /// Whether the text being fed (a whole document, or a paragraph) has had any tokens yet, even removed ones.
+    has_tokens: bool,
+    /// Whether the next line is the first of a document or paragraph, which may start with a byte order mark.
+    at_start: bool,
+    /// With [`BuildOptions::reverse`], the tokens of the current document, added backwards once it ends.
+    backwards: Vec<u32>,
+    /// How many times every transition is counted, see [`ModelBuilder::set_weight`].
+    weight: u32,
+}
+
+impl ModelBuilder {
+    /// Start building a model with the given state size.
+    pub fn new(state_size: usize, options: &BuildOptions) -> Self {
+        let mut tokens = Interner::default();
+        // Interned before anything else, just like CompactModel::build does
+        if options.sentinels {
+            tokens.intern(options.level.start_token());
+            tokens.intern(options.level.end_token());
+        }
+        ModelBuilder {
+            model: CompactModel {
+                tokens,
+                states: InternedStates::default(),
+                state_size,
+                level: options.level,
+                tokenization: options.tokenization,
+                lowercase: options.lowercase,
+                sentinels: options.sentinels,
+                reversed: options.reverse,
+                strip_markup: options.strip_markup,
+                mode: options.mode,
+                respect_paragraphs: options.respect_paragraphs,
+                stopwords: sorted_stopwords(options),
+                stopword_mode: options.stopword_mode,
+                filters: options.filters.clone(),
+                filter_mode: options.filter_mode,
+            },
+            options: options.clone(),
+            window: Vec::with_capacity(state_size + 1),
+            document_len: 0,
+            longest: 0,
+            total: 0,
+            has_tokens: false,
+            at_start: true,
+            backwards: Vec::new(),
+            weight: 1,
+        }
+    }
+
+    /// Count every transition from here on `weight` times instead of once, e.g. to make the style of one input
+    /// file count for more than the others. Changing it mid-document applies to the whole current document
+    /// when building backwards.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `weight` is 0.
+    pub fn set_weight(&mut self, weight: u32) {
+        assert!(weight > 0, "transition weights must be at least 1");
+        self.weight = weight;
+    }
+
+    /// Add a line of text to the current document, with or without its line ending.
+    ///
+    /// With [`BuildOptions::respect_paragraphs`], a blank line ends the document, just as [`tokenize_documents`]
+    /// splits paragraphs. With [`InputMode::Lines`], every line is a document of its own.
+    pub fn push_line(&mut self, line: &str) {
+        let line = if self.at_start { line.strip_prefix('\u{FEFF}').unwrap_or(line) } else { line };
+        if self.options.respect_paragraphs && line.trim().is_empty() {
+            self.end_document();
+            return;
+        }
+        self.at_start = false;
+
+        let mut tokens = Vec::new();
+        push_tokens(line, &self.options, &mut tokens);
+        if tokens.is_empty() {
+            return;
+        }
+        // Character-level text collapses the whitespace between words, line endings included, into a space
+        if self.options.level == Level::Char && self.has_tokens {
+            self.push_token(" ");
+        }
+        self.has_tokens = true;
+        // Every token of the line adds at most one state
+        self.model.states.reserve(tokens.len());
+        for token in &tokens {
+            self.push_token(token);
+        }
+        if self.options.mode == InputMode::Lines {
+            self.end_document();
+        }
+    }
+
+    /// End the current document, so no state spans it and the next line, e.g. at the end of an input file.
+    pub fn end_document(&mut self) {
+        self.close_document();
+        self.has_tokens = false;
+        self.at_start = true;
+    }
+
+    /// Number of tokens added so far, after removing stopwords and filtered tokens.
+    pub fn token_count(&self) -> usize {
+        self.total
+    }
+
+    /// End the current document and return the model, failing just like [`CompactModel::build`] if the input
+    /// was too short for a single transition.
+    pub fn finish(mut self) -> Result<CompactModel, MarkovError> {
+        self.end_document();
+        if self.total == 0 {
+            return Err(MarkovError::EmptyInput);
+        }
+        let state_size = self.model.state_size;
+        if self.longest < state_size + 1 {
+            return Err(MarkovError::InsufficientWords { found: self.longest, needed: state_size + 1, state_size, level: self.model.level });
+        }
+        if self.options.verbose {
+            eprintln!("Collected {} {} from input.", self.total, self.model.level.unit());
+            eprintln!("Markov chain construction complete. States: {}", self.model.states.len());
+        }
+        Ok(self.model)
+    }
+
+    fn push_token(&mut self, token: &str) {
+        let dropped = if !self.options.stopwords.is_empty() && self.options.stopwords.contains(&token.to_lowercase()) {
+            Some(self.options.stopword_mode)
+        } else if self.options.is_filtered(token) {
+            Some(self.options.filter_mode)
+        } else {
+            None
+        };
+        if let Some(mode) = dropped {
+            if mode == StopwordMode::Split {
+                self.close_document();
+            }
+            return;
+        }
+        let id = self.model.tokens.intern(token);
+        self.total += 1;
+        if self.options.reverse {
+            self.backwards.push(id);
+        } else {
+            self.add_token(id);
+        }
+    }
+
+    /// Add a token of the current document, after start sentinels if it's the first one.
+    fn add_token(&mut self, id: u32) {
+        if self.document_len == 0 && self.options.sentinels {
+            let start = self.model.tokens.intern(self.options.level.start_token());
+            self.window.extend(std::iter::repeat_n(start, self.model.state_size));
+            self.document_len = self.model.state_size;
+        }
+        self.add(id);
+    }
+
+    fn add(&mut self, id: u32) {
+        let state_size = self.model.state_size;
+        if self.window.len() == state_size {
+            if self.options.verbose {
+                eprintln!("State: '{}', Next: '{}'", self.model.tokens.join(&self.window, self.options.level), self.model.tokens.token(id));
+            }
+            record_transition(&mut self.model.states, &self.window, id, self.weight);
+        }
+        self.window.push(id);
+        if self.window.len() > state_size {
+            self.window.remove(0);
+        }
+        self.document_len += 1;
+    }
+
+    /// End the current document without starting a new paragraph or input, like a stopword in
+    /// [`StopwordMode::Split`] does.
+    fn close_document(&mut self) {
+        for id in std::mem::take(&mut self.backwards).into_iter().rev() {
+            self.add_token(id);
+        }
+        if self.document_len > 0 && self.options.sentinels {
+            let end = self.model.tokens.intern(self.options.level.end_token());
+            self.add(end);
+        }
+        self.longest = self.longest.max(self.document_len);
+        self.document_len = 0;
+        self.window.clear();
+    }
+}
+
+/// Record the transitions into each token of `input_vec` whose index is in `range`, `weight` times each.
+fn add_transitions(states: &mut InternedStates, input_vec: &[u32], range: Range<usize>, state_size: usize, weight: u32, tokens: &Interner, options: &BuildOptions) {
+    // Cook up the Markov Chain with given state_size
+    for i in range {
+        let state = &input_vec[i-state_size..i];
+        let current_word = input_vec[i];
+        if options.verbose {
+            eprintln!("State: '{}', Next: '{}'", tokens.join(state, options.level), tokens.token(current_word));
+        }
+        record_transition(states, state, current_word, weight);
+    }
+}
+
+/// Count a transition from `state` into `next`, `weight` times.
+fn record_transition(states: &mut InternedStates, state: &[u32], next: u32, weight: u32) {
+    // Only a state seen for the first time is copied into a key of its own
+    if let Some(successors) = states.get_mut(state) {
+        match successors.iter_mut().find(|(word, _)| *word == next) {
+            Some((_, count)) => *count = count.saturating_add(weight),
+            None => successors.push((next, weight)),
+        }
+    } else {
+        states.insert(state.into(), vec![(next, weight)]);
+    }
+}
+
+/// Build the states on `threads` threads (0 for one per core), each covering a chunk of the transitions.
+///
+/// Chunks are split by the index of the token being transitioned into, so states straddling a chunk
+/// boundary are still read from the full document. Merging the partial states in order keeps every
+/// state's successors in the order they were first seen, just like a sequential build.
+fn build_states_parallel(documents: &[Vec<u32>], weights: &[u32], state_size: usize, tokens: &Interner, options: &BuildOptions, threads: usize) -> Result<InternedStates, MarkovError> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map_err(std::io::Error::other)?;
+
+    let transitions: usize = documents.iter().map(|input_vec| input_vec.len().saturating_sub(state_size)).sum();
+    // One chunk per thread, since every extra chunk adds another merge
+    let chunk_size = transitions.div_ceil(pool.current_num_threads()).max(1);
+    let mut chunks: Vec<(&[u32], Range<usize>, u32)> = Vec::new();
+    for (input_vec, &weight) in documents.iter().zip(weights) {
+        let mut start = state_size;
+        while start < input_vec.len() {
+            let end = (start + chunk_size).min(input_vec.len());
+            chunks.push((input_vec, start..end, weight));
+            start = end;
+        }
+    }
+
+    // Rayon reduces neighbouring chunks in order, so left-to-right merging is preserved
+    let states = pool.install(|| {
+        chunks.into_par_iter()
+            .map(|(input_vec, range, weight)| {
+                let mut partial = InternedStates::with_capacity_and_hasher(range.len(), FxBuildHasher);
+                add_transitions(&mut partial, input_vec, range, state_size, weight, tokens, options);
+                partial
+            })
+            .reduce_with(|mut states, partial| {
+                merge_states(&mut states, partial);
+                states
+            })
+    });
+    Ok(states.unwrap_or_default())
+}
+
+/// Text produced by [`generate_text_with_metadata`], along with how it was produced.
+#[derive(Clone, Debug, Serialize)]
+pub struct GeneratedText {
+    /// The generated text.
+    pub text: String,
+    /// Number of tokens in `text`, including the starter or prompt unless [`GenerateOptions::omit_prompt`] is set.
+    pub tokens: usize,
+    /// Number of tokens generated after the starter or prompt.
+    pub sampled: usize,
+    /// Whether generation stopped at a dead-end (or an end sentinel), rather than at one of the limits.
+    pub dead_end: bool,
+    /// Whether generation was cut short by [`GenerateOptions::interrupt`].
+    pub interrupted: bool,
+    /// The state generation started from: a random starter, or the state taken from the end of the prompt.
+    pub starter: String,
+    /// Every token sampled, in order, if [`GenerateOptions::trace`] is set. Tokens of the starter or prompt
+    /// aren't sampled and so aren't in here, while tokens dropped again by `max_repeat`, `max_words` or `max_chars`
+    /// and a sampled end sentinel are.
+    pub trace: Vec<TraceStep>,
+}
+
+/// One sampled token of a [`GeneratedText::trace`].
+#[derive(Clone, Debug, Serialize)]
+pub struct TraceStep {
+    /// The state the token was sampled from.
+    pub state: String,
+    /// Number of distinct successors the token was sampled from, after `top_k` and `top_p`.
+    pub candidates: usize,
+    /// The sampled token.
+    pub token: String,
+    /// The probability the token had of being sampled, after `temperature`, `top_k` and `top_p`.
+    /// Close to 1 where the model is confident, close to `1 / candidates` where it's guessing.
+    pub probability: f64,
+}
+
+/// Generate text from `model`, drawing all randomness from `rng`.
+///
+/// Any [`Rng`] works: pass a seeded one such as `StdRng::seed_from_u64` for reproducible output.
+pub fn generate_text<R: Rng + ?Sized>(model: &MarkovModel, options: &GenerateOptions, rng: &mut R) -> Result<String, MarkovError> {
+    generate_text_with_metadata(model, options, rng).map(|generated| generated.text)
+}
+
+/// Like [`generate_text`], but also report the starter and the number of tokens generated.
+pub fn generate_text_with_metadata<R: Rng + ?Sized>(model: &MarkovModel, options: &GenerateOptions, rng: &mut R) -> Result<GeneratedText, MarkovError> {
+    let mut generator = Generator::new(model, options, rng)?;
+    let starter = generator.starter.clone();
+    let mut output_vec: Vec<String> = generator.pending.drain(..).collect();
+    let start_len = output_vec.len();
+    let prompt_len = if options.prompt.is_some() { start_len } else { 0 };
+    // Where the tokens counted towards the limits start
+    let counted = if options.omit_prompt { prompt_len } else { 0 };
+
+    // Loop detection only looks at what was generated since the last jump, so every jump makes progress
+    let mut loop_start = 0;
+    let mut restarts = 0;
+    let mut dead_end = false;
+    let mut interrupted = false;
+
+    // Get next state based on starter word
+    let count_sentences = |tokens: &[String]| tokens.iter().filter(|token| ends_sentence(token, &options.sentence_terminators)).count();
+    let mut sentences = count_sentences(&output_vec[counted..]);
+    while keep_generating(&output_vec[counted..], sentences, model.reversed, options) {
+        if options.is_interrupted() {
+            interrupted = true;
+            break;
+        }
+        let next_word = match generator.advance()? {
+            Some(word) => word,
+            None if restarts < options.retries
+                || (options.min_words.is_some_and(|min_words| output_vec.len() - counted < min_words) && restarts < MAX_RESTARTS) => {
+                // Too short to stop here, carry on from a fresh starter
+                restarts += 1;
+                let starter = generator.jump()?;
+                if options.verbose {
+                    eprintln!("Dead-end after {} tokens, restarting from starter '{}' (restart {})", output_vec.len(), starter, restarts);
+                }
+                output_vec.extend(generator.state_tokens());
+                sentences = count_sentences(&output_vec[counted..]);
+                loop_start = output_vec.len();
+                continue;
+            }
+            None => {
+                if options.verbose {
+                    eprintln!("Stopping generation.");
+                }
+                dead_end = true;
+                break;
+            }
+        };
+        if ends_sentence(&next_word, &options.sentence_terminators) {
+            // Going backwards, a token ending a sentence belongs to the sentence before, so stop in front of it
+            if model.reversed {
+                let done = match options.sentences {
+                    Some(target) => sentences >= target,
+                    None => options.end_at_sentence && output_vec.len() - counted >= options.max_words,
+                };
+                if done {
+                    break;
+                }
+            }
+            sentences += 1;
+        }
+        if let Some(max_chars) = options.max_chars
+            && overflows(&output_vec[counted..], &next_word, max_chars, model, options) {
+            if options.verbose {
+                eprintln!("Next word '{}' would make the text longer than {} characters, stopping generation.", next_word, max_chars);
+            }
+            break;
+        }
+        output_vec.push(next_word);
+
+        if let Some(max_repeat) = options.max_repeat
+            && let Some(length) = repeated_block_length(&output_vec[loop_start..], max_repeat) {
+            // Stuck in a loop, drop the repetition that went over the limit and start afresh
+            output_vec.truncate(output_vec.len() - length);
+            let starter = generator.jump()?;
+            if options.verbose {
+                eprintln!("Loop of {} tokens repeated more than {} times, jumping to starter '{}'", length, max_repeat, starter);
+            }
+            output_vec.extend(generator.state_tokens());
+            sentences = count_sentences(&output_vec[counted..]);
+            loop_start = output_vec.len();
+        }
+    }
+
+    if options.verbose && restarts > 0 {
+        eprintln!("Restarted {} times after dead-ends.", restarts);
+    }
+
+    // A starter longer than max_words, or one added after a jump near the end, can overshoot the limit
+    if options.sentences.is_none() && options.max_chars.is_none() {
+        let limit = if options.end_at_sentence { options.max_words + MAX_SENTENCE_OVERRUN } else { options.max_words };
+        output_vec.truncate((counted + limit).max(prompt_len));
+    }
+    let sampled = output_vec.len().saturating_sub(start_len);
+    output_vec.drain(..counted);
+    if model.reversed {
+        output_vec.reverse();
+    }
+
+    if let Some(filter) = options.strip_proper_nouns
+        && model.level == Level::Word {
+        strip_proper_nouns(&mut output_vec, filter, &options.sentence_terminators);
+    }
+
+    let separator = options.separator(model.level);
+    let join = |tokens: &[String]| {
+        let text = join_tokens(tokens, model.level, model.tokenization, separator);
+        let text = if options.trim_output { trim_output(&text, &options.sentence_terminators) } else { text };
+        if options.capitalize_start { capitalize_first(&text) } else { text }
+    };
+    let mut output = join(&output_vec);
+    // Starters added after a jump aren't checked as they go, and quotes may join differently at the end
+    if let Some(max_chars) = options.max_chars {
+        while output.chars().count() > max_chars && !output_vec.is_empty() {
+            // Backward text was generated from its end, so its start is what has to go
+            if model.reversed {
+                output_vec.remove(0);
+            } else {
+                output_vec.pop();
+            }
+            output = join(&output_vec);
+        }
+    }
+    Ok(GeneratedText { text: output, tokens: output_vec.len(), sampled, dead_end, interrupted, starter, trace: generator.trace })
+}
+
+/// Abbreviated titles that come before a name, so a full stop after them doesn't end a sentence.
+const TITLE_ABBREVIATIONS: &[&str] = &["Mr", "Mrs", "Ms", "Dr", "Prof", "Rev", "St", "Capt", "Col", "Gen", "Lt", "Sgt"];
+
+/// Drop or lowercase every word-level token that starts with a capital letter but doesn't start a sentence,
+/// i.e. isn't the first word or the first one after a token ending with one of `terminators`.
+///
+/// This is only a heuristic: acronyms are caught too, and names that do start a sentence are kept. The pronoun
+/// `I` and its contractions are left alone, and a full stop after a title such as `Mr.` doesn't end a sentence.
+/// Punctuation around a dropped word is kept, attached to the words next to it.
+pub fn strip_proper_nouns(tokens: &mut Vec<String>, filter: ProperNounFilter, terminators: &str) {
+    let mut kept: Vec<String> = Vec::with_capacity(tokens.len());
+    let mut at_sentence_start = true;
+    let mut after_title = false;
+    let mut dropped_title = false;
+    // Leading punctuation of a dropped word, for the next word
+    let mut prefix = String::new();
+    for mut token in tokens.drain(..) {
+        // Punctuation on its own neither starts nor continues a sentence, unless it ends one
+        if !token.chars().any(char::is_alphanumeric) {
+            at_sentence_start |= !after_title && ends_sentence(&token, terminators);
+            // A full stop after a dropped title has nothing left to abbreviate
+            if !(std::mem::take(&mut dropped_title) && token == ".") {
+                kept.push(token);
+            }
+            after_title = false;
+            continue;
+        }
+        let is_punctuation = |c: char| !c.is_alphanumeric();
+        let start = token.len() - token.trim_start_matches(is_punctuation).len();
+        let end = token.trim_end_matches(is_punctuation).len();
+        let core = &token[start..end];
+        let title = TITLE_ABBREVIATIONS.contains(&core);
+        let starts_sentence = std::mem::replace(&mut at_sentence_start, !title && ends_sentence(&token, terminators));
+        after_title = title;
+        dropped_title = false;
+
+        let pronoun = core == "I" || core.starts_with("I'") || core.starts_with("I\u{2019}");
+        if starts_sentence || pronoun || !core.chars().next().is_some_and(is_capital) {
+            kept.push(std::mem::take(&mut prefix) + &token);
+            continue;
+        }
+        match filter {
+            ProperNounFilter::Drop => {
+                prefix.push_str(&token[..start]);
+                let suffix = &token[end..];
+                let suffix = if title { suffix.strip_prefix('.').unwrap_or(suffix) } else { suffix };
+                if let Some(last) = kept.last_mut() {
+                    // Don't double up punctuation the previous word already ends with, as in `Now, Holmes,`
+                    let last_char = last.chars().next_back();
+                    last.push_str(suffix.trim_start_matches(|c| last_char == Some(c)));
+                }
+                dropped_title = title;
+            }
+            ProperNounFilter::Lowercase => {
+                token = token.to_lowercase();
+                kept.push(std::mem::take(&mut prefix) + &token);
+            }
+        }
+    }
+    *tokens = kept;
+}
+
+/// `text` with its first alphabetic character uppercased, leaving anything before it untouched.
+fn capitalize_first(text: &str) -> String {
+    match text.char_indices().find(|(_, c)| c.is_alphabetic()) {
+        Some((index, c)) => {
+            let mut capitalized = String::with_capacity(text.len());
+            capitalized.push_str(&text[..index]);
+            capitalized.extend(c.to_uppercase());
+            capitalized.push_str(&text[index + c.len_utf8()..]);
+            capitalized
+        }
+        None => text.to_string(),
+    }
+}
+
+/// Punctuation that leaves a text looking unfinished when it comes last, unless it's one of the sentence terminators.
+const DANGLING_PUNCTUATION: &[char] = &[',', ';', ':', '-', '\u{2013}', '\u{2014}', '/', '&', '\u{2026}'];
+
+/// `text` with the opening quotes and brackets that are never closed removed, and then any trailing punctuation that
+/// doesn't end a sentence (by `terminators`), such as a comma or a dash, along with trailing whitespace.
+///
+/// A straight double quote opens a quotation at the start of the text or after whitespace or an opening bracket,
+/// and closes one anywhere else. Closing quotes and brackets that were never opened are kept, since generated text
+/// often starts in the middle of a quotation, and single quotes are left alone, since they double as apostrophes.
+pub fn trim_output(text: &str, terminators: &str) -> String {
+    let mut unclosed: Vec<(usize, char)> = Vec::new();
+    let mut previous: Option<char> = None;
+    for (index, c) in text.char_indices() {
+        let opening = match c {
+            '(' | '[' | '{' | '\u{201C}' => None,
+            ')' => Some('('),
+            ']' => Some('['),
+            '}' => Some('{'),
+            '\u{201D}' => Some('\u{201C}'),
+            '"' if previous.is_none_or(|previous| previous.is_whitespace() || "([{".contains(previous)) => None,
+            '"' => Some('"'),
+            _ => {
+                previous = Some(c);
+                continue;
+            }
+        };
+        match opening {
+            None => unclosed.push((index, c)),
+            Some(opening) => {
+                if let Some(position) = unclosed.iter().rposition(|(_, open)| *open == opening) {
+                    unclosed.remove(position);
+                }
+            }
+        }
+        previous = Some(c);
+    }
+
+    let mut trimmed: String = text.char_indices()
+        .filter(|(index, _)| !unclosed.iter().any(|(open, _)| open == index))
+        .map(|(_, c)| c)
+        .collect();
+    loop {
+        let end = trimmed.trim_end().len();
+        trimmed.truncate(end);
+        match trimmed.chars().next_back() {
+            Some(c) if DANGLING_PUNCTUATION.contains(&c) && !terminators.contains(c) => trimmed.truncate(end - c.len_utf8()),
+            _ => return trimmed,
+        }
+    }
+}
+
+/// Whether appending `next_word` to `output_vec` would make the text longer than `max_chars` characters.
+fn overflows(output_vec: &[String], next_word: &str, max_chars: usize, model: &MarkovModel, options: &GenerateOptions) -> bool {
+    // Joining tokens only ever leaves out separators, so this cheap count is an upper bound
+    let separator = options.separator(model.level);
+    let separators = output_vec.len() * separator.chars().count();
+    let upper_bound: usize = output_vec.iter().map(|token| token.chars().count()).sum::<usize>()
+        + next_word.chars().count() + separators;
+    if upper_bound <= max_chars {
+        return false;
+    }
+    let mut candidate = output_vec.to_vec();
+    candidate.push(next_word.to_string());
+    join_tokens(&candidate, model.level, model.tokenization, separator).chars().count() > max_chars
+}
+
+/// Lazily generates text from a model, one token per call to [`Iterator::next`].
+///
+/// The tokens of the starter (or prompt) come first, then one sampled successor at a time until the
+/// current state has none. Only the sampling options of [`GenerateOptions`] apply here: length limits,
+/// `max_repeat` and `min_words` are up to the caller, e.g. with [`Iterator::take`] and [`Generator::restart`].
+pub struct Generator<'a, R: Rng> {
+    model: &'a MarkovModel,
+    options: &'a GenerateOptions,
+    rng: R,
+    backoff_chains: Vec<Chain>,
+    /// The state the next token is sampled from.
+    state: Vec<String>,
+    /// Tokens of the starter or prompt that haven't been yielded yet.
+    pending: VecDeque<String>,
+    /// The state generation started from.
+    starter: String,
+    /// Every token sampled so far, if `options.trace` is set.
+    trace: Vec<TraceStep>,
+    /// The last tokens yielded or about to be, as many as `options.verbatim` looks at.
+    recent: VecDeque<String>,
+}
+
+impl<'a, R: Rng> Generator<'a, R> {
+    /// Start generating from `model`, either from `options.prompt` or from a random starter.
+    pub fn new(model: &'a MarkovModel, options: &'a GenerateOptions, mut rng: R) -> Result<Self, MarkovError> {
+        options.validate()?;
+        let level = model.level;
+        if let Some(weights) = &options.interpolate
+            && weights.len() != model.state_size {
+            return Err(MarkovError::InvalidOption(format!("Interpolation needs {} weights, one for each order from 1 to {}, but got {}",
+                                                          model.state_size, model.state_size, weights.len())));
+        }
+        if let Some(verbatim) = &options.verbatim
+            && verbatim.max_verbatim() <= model.state_size {
+            return Err(MarkovError::InvalidOption(format!("Max verbatim must be more than the state size ({}), since every transition copies that many tokens and one more",
+                                                          model.state_size)));
+        }
+
+        let (start_vec, state) = match &options.prompt {
+            Some(prompt) => {
+                let mut prompt_vec = model.tokenize(prompt);
+                // A backward model continues from the start of the prompt, towards the beginning of the text
+                if model.reversed {
+                    prompt_vec.reverse();
+                }
+                let state = get_prompt_state(model, &prompt_vec, options, &mut rng)?;
+                (prompt_vec, state)
+            }
+            None => {
+                // Get a valid starting character with a capital letter.
+                let starter = get_text_starter_with(model, options, &mut rng)?;
+                if options.verbose {
+                    eprintln!("Starter chosen: '{}'", starter);
+                }
+                let starter_vec = split_state(&starter, level);
+                (starter_vec.clone(), starter_vec)
+            }
+        };
+        let backoff_chains = if options.backoff || options.interpolate.is_some() { build_backoff_chains(&model.chain, model.state_size, level) } else { Vec::new() };
+
+        let pending: VecDeque<String> = start_vec.into_iter().filter(|token| !level.is_sentinel(token)).collect();
+        let mut generator = Generator {
+            model,
+            options,
+            rng,
+            backoff_chains,
+            starter: state.join(level.separator()),
+            state,
+            recent: VecDeque::new(),
+            pending: pending.clone(),
+            trace: Vec::new(),
+        };
+        generator.remember(pending);
+        Ok(generator)
+    }
+
+    /// Add `tokens` to the ones [`GenerateOptions::verbatim`] looks at.
+    fn remember(&mut self, tokens: impl IntoIterator<Item = String>) {
+        if let Some(verbatim) = &self.options.verbatim {
+            self.recent.extend(tokens);
+            let excess = self.recent.len().saturating_sub(verbatim.max_verbatim());
+            self.recent.drain(..excess);
+        }
+    }
+
+    /// The tokens of the current state, without any sentinels.
+    fn state_tokens(&self) -> impl Iterator<Item = String> + '_ {
+        self.state.iter().filter(|token| !self.model.level.is_sentinel(token)).cloned()
+    }
+
+    /// The state generation started from: a random starter, or the state taken from the end of the prompt.
+    pub fn starter(&self) -> &str {
+        &self.starter
+    }
+
+    /// Every token sampled so far, if [`GenerateOptions::trace`] is set.
+    pub fn trace(&self) -> &[TraceStep] {
+        &self.trace
+    }
+
+    /// Continue from a fresh random starter, whose tokens are yielded next.
+    ///
+    /// Useful after a dead-end, to keep going instead of stopping.
+    pub fn restart(&mut self) -> Result<(), MarkovError> {
+        self.jump()?;
+        self.pending = self.state_tokens().collect();
+        Ok(())
+    }
+
+    /// Move to a fresh random starter without yielding its tokens, returning it.
+    fn jump(&mut self) -> Result<String, MarkovError> {
+        let starter = get_text_starter_with(self.model, self.options, &mut self.rng)?;
+        self.state = split_state(&starter, self.model.level);
+        // The starter's tokens follow on from the text so far
+        self.remember(self.state_tokens().collect::<Vec<_>>());
+        Ok(starter)
+    }
+
+    /// Sample the next token from the current state, or `None` on a dead-end.
+    fn advance(&mut self) -> Result<Option<String>, MarkovError> {
+        let (level, verbose) = (self.model.level, self.options.verbose);
+        let previous_words = self.state.join(level.separator());
+        if verbose {
+            eprintln!("Current state: '{}'", previous_words);
+        }
+        let chosen = if let Some(weights) = &self.options.interpolate {
+            let successors = interpolated_successors(&self.model.chain, &self.backoff_chains, &self.state, weights, level);
+            let successors = original_successors(&successors, &self.recent, self.model, self.options);
+            if successors.is_empty() {
+                None
+            } else {
+                Some(choose_successor(&successors, self.options, &mut self.rng).map(|(word, choice)| (word.clone(), choice)))
+            }
+        } else {
+            self.model.chain.get(&previous_words)
+                .or_else(|| self.options.case_insensitive_lookup
+                    .then(|| self.model.find_state_ignoring_case(&previous_words))
+                    .flatten()
+                    .map(|state| &self.model.chain[state]))
+                .or_else(|| backoff_successors(&self.backoff_chains, &self.state, level, verbose))
+                .map(|words| (words.is_empty(), original_successors(words, &self.recent, self.model, self.options)))
+                // Only a state that had successors to begin with can be left without any
+                .filter(|(empty, words)| *empty || !words.is_empty())
+                .map(|(_, words)| choose_successor(&words, self.options, &mut self.rng).map(|(word, choice)| (word.clone(), choice)))
+        };
+        let next_word = match chosen {
+            Some(chosen) => {
+                let (chosen, choice) = chosen.ok_or_else(|| MarkovError::EmptyState(previous_words.clone()))?;
+                if verbose {
+                    eprintln!("Next word chosen: '{}'", chosen);
+                }
+                if self.options.trace {
+                    self.trace.push(TraceStep {
+                        state: previous_words,
+                        candidates: choice.candidates,
+                        token: chosen.clone(),
+                        probability: choice.probability,
+                    });
+                }
+                if self.model.sentinels && chosen == level.end_token() {
+                    if verbose {
+                        eprintln!("Reached the end of a document.");
+                    }
+                    return Ok(None);
+                }
+                chosen
+            }
+            None => {
+                if verbose {
+                    eprintln!("No next word found for state '{}'.", previous_words);
+                }
+                return Ok(None);
+            }
+        };
+        // An order 0 model has no context to shift the token into
+        if !self.state.is_empty() {
+            self.state.remove(0);
+            self.state.push(next_word.clone());
+        }
+        self.remember([next_word.clone()]);
+        Ok(Some(next_word))
+    }
+}
+
+impl<R: Rng> Iterator for Generator<'_, R> {
+    type Item = String;
+
+    /// The next token, or `None` once the current state has no successors.
+    fn next(&mut self) -> Option<String> {
+        if let Some(token) = self.pending.pop_front() {
+            return Some(token);
+        }
+        // A state without any successors can only come from a malformed model; treat it as a dead-end.
+        self.advance().ok().flatten()
+    }
+}
+
+/// Lazily generates complete sentences from a model, one per call to [`Iterator::next`], without end.
+///
+/// Each sentence runs up to and including a token ending with one of [`GenerateOptions::sentence_terminators`],
+/// and the next one carries on from there. On a dead-end the unfinished sentence is dropped and generation
+/// restarts from a fresh starter, so the stream only ends once [`MAX_RESTARTS`] restarts in a row fail to
+/// finish a sentence within [`MAX_SENTENCE_LENGTH`] tokens. The first sentence and those after a restart
+/// start wherever `start_strategy` says, so [`StartStrategy::Sentence`] keeps them from starting mid-sentence.
+///
+/// This only works well with a model where many tokens end a sentence, ideally one built with
+/// [`Tokenization::Punctuation`] so that terminators are tokens of their own.
+pub struct Sentences<'a, R: Rng> {
+    generator: Generator<'a, R>,
+}
+
+impl<'a, R: Rng> Sentences<'a, R> {
+    /// Start generating sentences from `model`, see [`Generator::new`]. Backward models aren't supported.
+    pub fn new(model: &'a MarkovModel, options: &'a GenerateOptions, rng: R) -> Result<Self, MarkovError> {
+        if model.reversed {
+            return Err(MarkovError::InvalidOption("Sentences can't be generated from a model built backwards".to_string()));
+        }
+        Ok(Sentences { generator: Generator::new(model, options, rng)? })
+    }
+}
+
+impl<R: Rng> Iterator for Sentences<'_, R> {
+    type Item = String;
+
+    /// The next complete sentence, or `None` if the model keeps failing to finish one.
+    fn next(&mut self) -> Option<String> {
+        let (model, options) = (self.generator.model, self.generator.options);
+        let mut tokens = Vec::new();
+        let mut restarts = 0;
+        loop {
+            if let Some(token) = self.generator.next() {
+                // Character-level text has a space between one sentence and the next
+                if tokens.is_empty() && token.trim().is_empty() {
+                    continue;
+                }
+                let done = ends_sentence(&token, &options.sentence_terminators);
+                tokens.push(token);
+                if done {
+                    return Some(join_tokens(&tokens, model.level, model.tokenization, options.separator(model.level)));
+                }
+                if tokens.len() < MAX_SENTENCE_LENGTH {
+                    continue;
+                }
+            }
+            // A dead-end or a sentence that never ends, start afresh
+            restarts += 1;
+            if restarts > MAX_RESTARTS {
+                return None;
+            }
+            tokens.clear();
+            self.generator.restart().ok()?;
+        }
+    }
+}
+
+/// `successors`, without those that would make the text copy more than [`GenerateOptions::verbatim`] allows
+/// after the `recent` tokens.
+fn original_successors<'s, W: Clone>(successors: &'s [(String, W)], recent: &VecDeque<String>, model: &MarkovModel, options: &GenerateOptions) -> Cow<'s, [(String, W)]> {
+    let Some(verbatim) = &options.verbatim else {
+        return Cow::Borrowed(successors);
+    };
+    let original: Vec<(String, W)> = successors.iter()
+        .filter(|(word, _)| !verbatim.copies(recent, word, model.reversed))
+        .cloned()
+        .collect();
+    if options.verbose && original.len() < successors.len() {
+        eprintln!("Left out {} of {} next words that would copy more than {} tokens of the input.",
+                  successors.len() - original.len(), successors.len(), verbatim.max_verbatim());
+    }
+    Cow::Owned(original)
+}
+
+/// For every shorter state length `k` in `1..state_size`, a chain keyed by the last `k` tokens of
+/// the full states, with the successors of all states sharing that ending combined.
+///
+/// Index `k - 1` holds the chain for length `k`.
+fn build_backoff_chains(model: &Chain, state_size: usize, level: Level) -> Vec<Chain> {
+    let mut merged: Vec<HashMap<String, HashMap<&str, u32>>> = vec![HashMap::new(); state_size.saturating_sub(1)];
+    for (key, successors) in model {
+        let tokens = split_state(key, level);
+        for (k, chain) in merged.iter_mut().enumerate() {
+            let ending = tokens[tokens.len() - (k + 1)..].join(level.separator());
+            let combined = chain.entry(ending).or_default();
+            for (word, count) in successors {
+                *combined.entry(word).or_default() += count;
+            }
+        }
+    }
+
+    // Sort successors so sampling doesn't depend on HashMap iteration order
+    merged.into_iter()
+        .map(|chain| chain.into_iter()
+            .map(|(ending, combined)| {
+                let mut successors: Vec<(String, u32)> = combined.into_iter()
+                    .map(|(word, count)| (word.to_string(), count))
+                    .collect();
+                successors.sort();
+                (ending, successors)
+            })
+            .collect())
+        .collect()
+}
+
+/// Look up successors for the longest ending of `state` found in `backoff_chains`.
+fn backoff_successors<'a>(backoff_chains: &'a [Chain], state: &[String], level: Level, verbose: bool) -> Option<&'a Vec<(String, u32)>> {
+    for (k, chain) in backoff_chains.iter().enumerate().rev() {
+        let ending = state[state.len() - (k + 1)..].join(level.separator());
+        if let Some(successors) = chain.get(&ending) {
+            if verbose {
+                eprintln!("Backing off to state '{}'", ending);
+            }
+            return Some(successors);
+        }
+    }
+    None
+}
+
+/// The successors of `state` at every order from 1 to its length, with each order's probabilities
+/// multiplied by its entry in `weights` (lowest order first) and added up.
+///
+/// Orders whose ending isn't a state are left out, and the successors of higher orders come first.
+fn interpolated_successors(chain: &Chain, backoff_chains: &[Chain], state: &[String], weights: &[f64], level: Level) -> Vec<(String, f64)> {
+    let mut combined: Vec<(String, f64)> = Vec::new();
+    let mut positions: HashMap<&str, usize> = HashMap::new();
+    for (k, weight) in weights.iter().enumerate().rev() {
+        let ending = state[state.len() - (k + 1)..].join(level.separator());
+        let successors = if k + 1 == state.len() { chain.get(&ending) } else { backoff_chains[k].get(&ending) };
+        let Some(successors) = successors else {
+            continue;
+        };
+        let total: u32 = successors.iter().map(|(_, count)| count).sum();
+        if total == 0 || *weight == 0.0 {
+            continue;
+        }
+        for (word, count) in successors {
+            let probability = weight * *count as f64 / total as f64;
+            match positions.get(word.as_str()) {
+                Some(&position) => combined[position].1 += probability,
+                None => {
+                    positions.insert(word, combined.len());
+                    combined.push((word.clone(), probability));
+                }
+            }
+        }
+    }
+    combined
+}
+
+/// Find the state to continue a prompt from, applying `options.prompt_fallback` if needed.
+fn get_prompt_state<R: Rng + ?Sized>(model: &MarkovModel, prompt_vec: &[String], options: &GenerateOptions, rng: &mut R) -> Result<Vec<String>, MarkovError> {
+    if prompt_vec.is_empty() {
+        return Err(MarkovError::EmptyPrompt);
+    }
+    let (chain, state_size, level) = (&model.chain, model.state_size, model.level);
+
+    let tail = &prompt_vec[prompt_vec.len().saturating_sub(state_size)..];
+    if tail.len() == state_size && chain.contains_key(&tail.join(level.separator())) {
+        return Ok(tail.to_vec());
+    }
+    if options.case_insensitive_lookup
+        && tail.len() == state_size
+        && let Some(key) = model.find_state_ignoring_case(&tail.join(level.separator())) {
+        if options.verbose {
+            eprintln!("Prompt state not found, continuing from state '{}', which only differs in case", key);
+        }
+        return Ok(split_state(key, level));
+    }
+    if options.prompt_fallback == PromptFallback::Error {
+        let mut shown = tail.to_vec();
+        if model.reversed {
+            shown.reverse();
+        }
+        return Err(MarkovError::PromptNotFound(shown.join(level.separator())));
+    }
+
+    // Prefer states sharing the longest possible ending with the prompt
+    let lowercase = |tokens: &[String]| -> Vec<String> { tokens.iter().map(|token| token.to_lowercase()).collect() };
+    for shared in (1..=tail.len().min(state_size.saturating_sub(1))).rev() {
+        let ending = &tail[tail.len() - shared..];
+        let lowercase_ending = lowercase(ending);
+        let mut nearest: Vec<&String> = chain.keys()
+            .filter(|key| {
+                let tokens = split_state(key, level);
+                if options.case_insensitive_lookup {
+                    lowercase(&tokens).ends_with(&lowercase_ending)
+                } else {
+                    tokens.ends_with(ending)
+                }
+            })
+            .collect();
+        nearest.sort();
+        if let Some(key) = nearest.choose(rng) {
+            if options.verbose {
+                eprintln!("Prompt state not found, continuing from nearest state '{}'", key);
+            }
+            return Ok(split_state(key, level));
+        }
+    }
+
+    let starter = get_text_starter_with(model, options, rng)?;
+    if options.verbose {
+        eprintln!("No state resembles the end of the prompt, continuing from starter '{}'", starter);
+    }
+    Ok(split_state(&starter, level))
+}
+
+/// How a successor was picked by [`choose_successor`].
+struct Choice {
+    /// Number of successors left to pick from after `top_k` and `top_p`.
+    candidates: usize,
+    /// The probability the picked successor had.
+    probability: f64,
+}
+
+/// Sample one successor according to the sampling settings in `options`, weighted by its count
+/// (or by its interpolated probability).
+fn choose_successor<'a, W, R>(successors: &'a [(String, W)], options: &GenerateOptions, rng: &mut R) -> Option<(&'a String, Choice)>
+where
+    W: Copy + PartialOrd + Into<f64> + SampleUniform + Weight,
+    R: Rng + ?Sized,
+{
+    let mut candidates: Vec<&(String, W)> = successors.iter().collect();
+    let top_p = options.top_p.filter(|top_p| *top_p < 1.0);
+    if options.top_k.is_some() || top_p.is_some() {
+        candidates.sort_by(|(a_word, a_count), (b_word, b_count)| {
+            b_count.partial_cmp(a_count).unwrap_or(Ordering::Equal).then_with(|| a_word.cmp(b_word))
+        });
+    }
+    if let Some(top_k) = options.top_k {
+        candidates.truncate(top_k);
+    }
+    if let Some(top_p) = top_p {
+        // Keep the most likely successors until their combined probability first exceeds top_p
+        let total: f64 = candidates.iter().map(|(_, count)| (*count).into()).sum();
+        let mut cumulative = 0.0;
+        let keep = candidates.iter()
+            .position(|(_, count)| {
+                cumulative += (*count).into() / total;
+                cumulative > top_p
+            })
+            .map_or(candidates.len(), |i| i + 1);
+        candidates.truncate(keep);
+    }
+
+    let temperature = options.temperature;
+    let length_bias = options.length_bias;
+    // The log of the length factor, length^length_bias
+    let length = |word: &String| length_bias * (word.chars().count().max(1) as f64).ln();
+    let max = candidates.iter().map(|(_, count)| *count).reduce(|max, count| if count > max { count } else { max })?;
+    if temperature == 0.0 {
+        // Greedy: the most frequent successor, ties going to the one favoured by length_bias, then the one seen first
+        let candidates_left = candidates.len();
+        return candidates.into_iter()
+            .filter(|(_, count)| *count == max)
+            .reduce(|best, candidate| if length(&candidate.0) > length(&best.0) { candidate } else { best })
+            .map(|(word, _)| (word, Choice { candidates: candidates_left, probability: 1.0 }));
+    }
+    // Weight by count^(1 / temperature) * length^length_bias, relative to the largest count and length factor in log
+    // space so tiny temperatures and large biases don't overflow
+    let max: f64 = max.into();
+    let longest = candidates.iter().map(|(word, _)| length(word)).fold(f64::NEG_INFINITY, f64::max);
+    let unbiased = temperature == 1.0 && length_bias == 0.0;
+    let weight = |(word, count): &(String, W)| if unbiased {
+        (*count).into()
+    } else {
+        (((*count).into().ln() - max.ln()) / temperature + length(word) - longest).exp()
+    };
+    let chosen = if unbiased {
+        candidates.choose_weighted(rng, |(_, count)| *count).ok()?
+    } else {
+        candidates.choose_weighted(rng, |candidate| weight(candidate)).ok()?
+    };
+    let total: f64 = candidates.iter().map(|candidate| weight(candidate)).sum();
+    Some((&chosen.0, Choice { candidates: candidates.len(), probability: weight(chosen) / total }))
+}
+
+/// If `tokens` ends with the same block of tokens repeated more than `max_repeat` times in a row,
+/// the length of that block.
+fn repeated_block_length(tokens: &[String], max_repeat: usize) -> Option<usize> {
+    (1..=MAX_LOOP_LENGTH).find(|&length| {
+        let span = length * (max_repeat + 1);
+        if span > tokens.len() {
+            return false;
+        }
+        let tail = &tokens[tokens.len() - span..];
+        let block = &tail[span - length..];
+        tail.chunks(length).all(|chunk| chunk == block)
+    })
+}
+
+/// Whether to generate another token. A backward model stops at the end of a sentence in
+/// [`generate_text_with_metadata`] instead, before the token that ends the sentence in front.
+fn keep_generating(output_vec: &[String], sentences: usize, reversed: bool, options: &GenerateOptions) -> bool {
+    if let Some(target) = options.sentences {
+        return (sentences < target || reversed) && output_vec.len() < target * MAX_SENTENCE_LENGTH;
+    }
+    if options.max_chars.is_some() {
+        // Stopped by the length check in generate_text_with_metadata instead
+        return true;
+    }
+    if output_vec.len() < options.max_words {
+        return true;
+    }
+    options.end_at_sentence
+        && output_vec.len() < options.max_words + MAX_SENTENCE_OVERRUN
+        && (reversed || !output_vec.last().is_some_and(|token| ends_sentence(token, &options.sentence_terminators)))
+}
+
+/// Whether `c` is an uppercase or titlecase letter, like `A`, `Ê` or `ǅ`.
+fn is_capital(c: char) -> bool {
+    c.is_alphabetic() && !c.is_lowercase() && !c.to_lowercase().eq([c])
+}
+
+/// Whether `c` is a letter of a script without case, like `字` or `ب`.
+fn is_caseless(c: char) -> bool {
+    c.is_alphabetic() && !c.is_lowercase() && !is_capital(c)
+}
+
+/// States that directly followed a token ending a sentence (by `terminators`) in the training input.
+///
+/// A state `[t2, .., tk, s]` follows `t1` whenever `s` is a successor of `[t1, t2, .., tk]`.
+fn sentence_starters(model: &MarkovModel, terminators: &str) -> Vec<String> {
+    sentence_start_counts(model, terminators).into_keys().collect()
+}
+
+/// How often each state of [`sentence_starters`] followed the end of a sentence in the training input.
+fn sentence_start_counts(model: &MarkovModel, terminators: &str) -> HashMap<String, u64> {
+    let mut starters: HashMap<String, u64> = HashMap::new();
+    for (key, successors) in &model.chain {
+        let tokens = split_state(key, model.level);
+        if !tokens.first().is_some_and(|first| ends_sentence(first, terminators)) {
+            continue;
+        }
+        for (successor, count) in successors {
+            let mut state = tokens[1..].to_vec();
+            state.push(successor.clone());
+            let state = state.join(model.level.separator());
+            if model.chain.contains_key(&state) {
+                *starters.entry(state).or_default() += *count as u64;
+            }
+        }
+    }
+    starters
+}
+
+/// States of a backward model whose first token ends a sentence (by `terminators`), i.e. ones that end a sentence
+/// of the original text.
+fn sentence_enders(model: &MarkovModel, terminators: &str) -> Vec<String> {
+    model.chain.keys()
+        .filter(|key| split_state(key, model.level).first().is_some_and(|first| ends_sentence(first, terminators)))
+        .cloned()
+        .collect()
+}
+
+/// The state made up of start sentinels, where documents start, if the model has one.
+fn document_start(model: &MarkovModel) -> Option<String> {
+    let start = vec![model.level.start_token(); model.state_size].join(model.level.separator());
+    (model.sentinels && model.chain.contains_key(&start)).then_some(start)
+}
+
+/// Pick a random state to start generation from, preferring states that look like sentence starts.
+///
+/// For a lowercased model these are the states that followed the end of a sentence, otherwise the states
+/// starting with a capital letter. If there are none of those, any state of the model may be picked, which is
+/// still up to `rng` alone. This is [`StartStrategy::Capital`], see [`get_text_starter_with`] for the others.
+pub fn get_text_starter<R: Rng + ?Sized>(model: &MarkovModel, rng: &mut R) -> Result<String, MarkovError> {
+    get_text_starter_with(model, &GenerateOptions::default(), rng)
+}
+
+/// Pick a random state to start generation from, as `options.start_strategy` says.
+///
+/// [`StartStrategy::Sentence`] recognizes the end of a sentence by `options.sentence_terminators`, and so does
+/// [`GenerateOptions::weighted_start`], which multiplies with the weights of [`StartStrategy::Branchy`]. Weighted
+/// starters that never started a sentence aren't picked at all, unless none of them did, and then the pick is
+/// uniform after all.
+pub fn get_text_starter_with<R: Rng + ?Sized>(model: &MarkovModel, options: &GenerateOptions, rng: &mut R) -> Result<String, MarkovError> {
+    // Sorted so the pick only depends on the RNG, not on HashMap iteration order
+    let starters: Cow<[String]> = match options.start_strategy {
+        // Documents start with a state made up of start sentinels
+        StartStrategy::Capital | StartStrategy::Branchy => match document_start(model) {
+            Some(start) => return Ok(start),
+            None => Cow::Borrowed(model.capital_starters()),
+        },
+        StartStrategy::Sentence => {
+            let mut starters = if model.reversed {
+                sentence_enders(model, &options.sentence_terminators)
+            } else {
+                sentence_starters(model, &options.sentence_terminators)
+            };
+            if let Some(start) = document_start(model)
+                && !starters.contains(&start) {
+                starters.push(start);
+            }
+            if starters.is_empty() {
+                if options.verbose {
+                    eprintln!("No state follows the end of a sentence, starting from a capitalized state instead.");
+                }
+                Cow::Borrowed(model.capital_starters())
+            } else {
+                starters.sort();
+                Cow::Owned(starters)
+            }
+        }
+        StartStrategy::Random => {
+            let mut starters: Vec<String> = model.chain.keys().cloned().collect();
+            starters.sort();
+            Cow::Owned(starters)
+        }
+    };
+
+    if options.start_strategy == StartStrategy::Branchy && !options.weighted_start {
+        // Every state has a successor, so there's always something to pick
+        return starters.choose_weighted(rng, |starter| model.chain[starter].len())
+            .cloned()
+            .map_err(|_| MarkovError::EmptyModel);
+    }
+    if options.weighted_start {
+        let mut counts = sentence_start_counts(model, &options.sentence_terminators);
+        if let Some(start) = document_start(model) {
+            // Every document starts there once
+            let documents: u64 = model.chain[&start].iter().map(|(_, count)| *count as u64).sum();
+            *counts.entry(start).or_default() += documents;
+        }
+        // Branchy starters count once for each of their distinct successors
+        let branches = |starter: &String| if options.start_strategy == StartStrategy::Branchy { model.chain[starter].len() as u64 } else { 1 };
+        if let Ok(starter) = starters.choose_weighted(rng, |starter| counts.get(starter).copied().unwrap_or(0) * branches(starter)) {
+            return Ok(starter.clone());
+        }
+        if options.verbose {
+            eprintln!("None of the starting states ever started a sentence, picking one uniformly instead.");
+        }
+    }
+    starters.choose(rng).cloned().ok_or(MarkovError::EmptyModel)
+}
+
+/// The starters of [`StartStrategy::Capital`] for a model without a start sentinel state, sorted.
+fn find_capital_starters(model: &MarkovModel) -> Vec<String> {
+    let mut starters: Vec<String> = Vec::new();
+
+    if model.reversed {
+        // A backward model generates a sentence from its end, so use the states that start with the end of one
+        starters = sentence_enders(model, DEFAULT_SENTENCE_TERMINATORS);
+    } else if model.lowercase {
+        // A lowercased model has no capitals to go by, so use the states that followed the end of a sentence
+        starters = sentence_starters(model, DEFAULT_SENTENCE_TERMINATORS);
+    } else {
+        // Checks for capital letters in the start of the state. Scripts without case have no capitals,
+        // so any of their letters could start a sentence.
+        for (key, _value) in model.chain.iter() {
+            if let Some(first_char) = key.chars().next()
+                && (is_capital(first_char) || is_caseless(first_char)) {
+                starters.push(key.to_string());
+            }
+        }
+    }
+
+    // Check that the last word in the state doesn't start with a capital letter (to prevent proper nouns).
+    // Character-level states aren't made of words, and a single-word state's last word is its capitalized
+    // first word, so this only applies to word-level states of two or more words.
+    // If no starter passes, keep any starter with a capital letter.
+    let is_valid = |starter: &String| {
+        starter.split(' ').next_back()
+            .and_then(|last_word| last_word.chars().next())
+            .is_some_and(|first_char| !is_capital(first_char))
+    };
+    if model.level == Level::Word && model.state_size > 1 && !model.reversed && starters.iter().any(is_valid) {
+        starters.retain(is_valid);
+    }
+
+    // If still no starters, e.g. for a lowercased model without punctuation, any state of the model will do
+    if starters.is_empty() {
+        starters = model.chain.keys().cloned().collect();
+    }
+
+    starters.sort();
+    starters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn capital_starters_are_picked_uniformly() {
+        let model = MarkovModel::train("Alpha one two. Beta one two. Gamma one two. Delta one two.", 1).unwrap();
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for seed in 0..4000 {
+            let starter = get_text_starter(&model, &mut StdRng::seed_from_u64(seed)).unwrap();
+            *counts.entry(starter).or_default() += 1;
+        }
+        let mut starters: Vec<&str> = counts.keys().map(String::as_str).collect();
+        starters.sort();
+        assert_eq!(starters, ["Alpha", "Beta", "Delta", "Gamma"]);
+        for (starter, count) in &counts {
+            assert!((800..1200).contains(count), "'{}' was picked {} times out of 4000", starter, count);
+        }
+    }
+
+    #[test]
+    fn starters_are_capitalized_states_of_the_chain() {
+        let text = include_str!("../examples/sherlock.txt");
+        for state_size in 1..=3 {
+            let model = MarkovModel::train(text, state_size).unwrap();
+            let mut rng = StdRng::seed_from_u64(7);
+            for _ in 0..200 {
+                let starter = get_text_starter(&model, &mut rng).unwrap();
+                assert!(model.chain.contains_key(&starter), "'{}' is not a state", starter);
+                assert!(starter.chars().next().is_some_and(is_capital), "'{}' doesn't start with a capital", starter);
+                if state_size > 1 {
+                    let last_word = starter.split(' ').next_back().unwrap();
+                    assert!(!last_word.chars().next().is_some_and(is_capital), "'{}' ends with a proper noun", starter);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn loading_a_state_of_the_wrong_size_fails() {
+        let mut model = MarkovModel::train("The cat sat on the mat.", 2).unwrap();
+        model.chain_mut().insert("the cat sat".to_string(), vec![("on".to_string(), 1)]);
+        let json = model_to_json(&model).unwrap();
+        match model_from_json(&json) {
+            Err(MarkovError::StateSizeMismatch { state_size, found, state }) => {
+                assert_eq!((state_size, found, state.as_str()), (2, 3, "the cat sat"));
+            }
+            other => panic!("expected a state size mismatch, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn build_options_survive_saving() {
+        let options = BuildOptions {
+            level: Level::Char,
+            stopwords: ["b".to_string(), "a".to_string()].into_iter().collect(),
+            stopword_mode: StopwordMode::Split,
+            filters: vec![TokenFilter::Numbers],
+            mode: InputMode::Lines,
+            respect_paragraphs: true,
+            sentinels: true,
+            ..BuildOptions::default()
+        };
+        let model = MarkovModel::train_with("Anna\nBob\nCarla", 2, &options).unwrap();
+        for format in [ModelFormat::Json, ModelFormat::Bincode] {
+            let loaded = model_from_bytes(&model_to_bytes(&model, format).unwrap()).unwrap();
+            assert!(loaded.has_complete_build_options());
+            let restored = loaded.build_options();
+            assert_eq!((restored.mode, restored.respect_paragraphs, restored.stopword_mode), (InputMode::Lines, true, StopwordMode::Split));
+            assert_eq!((restored.stopwords, restored.filters), (options.stopwords.clone(), options.filters.clone()));
+        }
+
+        let old = model_from_json(r#"{"version":8,"state_size":1,"states":{"a":{"b":1}}}"#).unwrap();
+        assert!(!old.has_complete_build_options());
+        assert_eq!(old.build_options().mode, InputMode::Continuous);
+    }
+
+    #[test]
+    fn merged_counts_saturate() {
+        let chain = |count| Chain::from([("a".to_string(), vec![("b".to_string(), count), ("c".to_string(), 1)])]);
+        let mut model = chain(u32::MAX - 1);
+        merge_chain(&mut model, chain(5));
+        assert_eq!(model["a"], [("b".to_string(), u32::MAX), ("c".to_string(), 2)]);
+    }
+
+    #[test]
+    fn too_short_input_names_what_is_missing() {
+        let error = MarkovModel::train("one two", 3).unwrap_err();
+        assert_eq!(error.to_string(), "Input text has 2 words, but need at least 4 words for state size 3");
+    }
+
+    #[test]
+    fn byte_order_mark_is_stripped() {
+        let text = "\u{FEFF}The cat sat on the mat. The dog sat on the cat.";
+        let options = BuildOptions::default();
+        assert_eq!(tokenize(text, &options)[0], "The");
+
+        let mut builder = ModelBuilder::new(1, &options);
+        builder.push_line(text);
+        let streamed = builder.finish().unwrap().into_model();
+        for model in [MarkovModel::train(text, 1).unwrap(), streamed] {
+            assert!(model.chain.keys().all(|key| !key.contains('\u{FEFF}')));
+            let mut rng = StdRng::seed_from_u64(1);
+            for _ in 0..20 {
+                let starter = get_text_starter(&model, &mut rng).unwrap();
+                assert!(starter.starts_with('T'), "'{}' doesn't start with a capital", starter);
+            }
+        }
+    }
+
+    #[test]
+    fn titlecase_and_caseless_letters_start_sentences() {
+        assert!(is_capital('Ê') && is_capital('ǅ') && !is_capital('ê') && !is_capital('東'));
+        assert!(is_caseless('東') && is_caseless('ب') && !is_caseless('Ê') && !is_caseless('e'));
+
+        let model = MarkovModel::train("Être est là. ǅemal est là. la vie est là.", 1).unwrap();
+        assert_eq!(find_capital_starters(&model), ["Être", "ǅemal"]);
+        let model = MarkovModel::train("The cat is big. 東京 is big.", 1).unwrap();
+        assert_eq!(find_capital_starters(&model), ["The", "東京"]);
+    }
+
+    #[test]
+    fn punctuation_is_split_off_words() {
+        let split = |word: &str| {
+            let mut tokens = Vec::new();
+            split_punctuation(word, &mut tokens);
+            tokens
+        };
+        assert_eq!(split("don't,"), ["don't", ","]);
+        assert_eq!(split("mother-in-law."), ["mother-in-law", "."]);
+        assert_eq!(split("'quoted'"), ["'", "quoted", "'"]);
+        assert_eq!(split("it\u{2019}s"), ["it\u{2019}s"]);
+        assert_eq!(split("1,000."), ["1,000", "."]);
+        assert_eq!(split("(e.g."), ["(", "e.g", "."]);
+    }
+
+    #[test]
+    fn saved_models_are_byte_for_byte_reproducible() {
+        let text = include_str!("../examples/sherlock.txt");
+        let first = MarkovModel::train(text, 2).unwrap();
+        let second = MarkovModel::train(text, 2).unwrap();
+        for format in [ModelFormat::Json, ModelFormat::Bincode] {
+            let (a, b) = (model_to_bytes(&first, format).unwrap(), model_to_bytes(&second, format).unwrap());
+            assert!(a == b, "{:?} models differ", format);
+            assert!(gzip(&a).unwrap() == gzip(&b).unwrap(), "gzipped {:?} models differ", format);
+        }
+    }
+
+    #[test]
+    fn proper_nouns_are_dropped_or_lowercased() {
+        let text = "Then Holmes and I met Mr. Watson at Baker Street. Watson said I'm sure it was Lestrade.";
+        let strip = |filter| {
+            let mut tokens: Vec<String> = text.split(' ').map(str::to_string).collect();
+            strip_proper_nouns(&mut tokens, filter, DEFAULT_SENTENCE_TERMINATORS);
+            tokens.join(" ")
+        };
+        // The full stop after `Mr.` doesn't end the sentence, so the name after it goes too
+        assert_eq!(strip(ProperNounFilter::Drop), "Then and I met at. Watson said I'm sure it was.");
+        assert_eq!(strip(ProperNounFilter::Lowercase),
+                   "Then holmes and I met mr. watson at baker street. Watson said I'm sure it was lestrade.");
+    }
+
+    #[test]
+    fn characters_are_whole_graphemes() {
+        let options = BuildOptions { level: Level::Char, ..BuildOptions::default() };
+        // A waving hand with a skin tone modifier, and an `e` followed by a combining acute accent
+        let text = "hi \u{1F44B}\u{1F3FD} cafe\u{301}";
+        assert_eq!(tokenize(text, &options), ["h", "i", " ", "\u{1F44B}\u{1F3FD}", " ", "c", "a", "f", "e\u{301}"]);
+        assert_eq!(split_state("\u{1F44B}\u{1F3FD}e\u{301}", Level::Char), ["\u{1F44B}\u{1F3FD}", "e\u{301}"]);
+    }
+
+    #[test]
+    fn max_words_counts_the_starter() {
+        // Every state also occurs earlier on, so generation never hits a dead-end
+        let text = "The cat sat on the mat and the dog sat on the rug. ".repeat(4);
+        for state_size in 1..=4 {
+            let model = MarkovModel::train(&text, state_size).unwrap();
+            for max_words in (1..state_size).chain([state_size, 10, 25]) {
+                let options = GenerateOptions { max_words, ..GenerateOptions::default() };
+                let generated = model.generate_with(&options, &mut StdRng::seed_from_u64(3)).unwrap();
+                assert_eq!(generated.tokens, max_words, "state size {}", state_size);
+                assert_eq!(generated.text.split(' ').count(), max_words, "state size {}", state_size);
+            }
+        }
+    }
+
+    #[test]
+    fn lowercase_models_still_have_starters() {
+        let text = "the cat sat on the mat and the dog sat on the rug";
+        let lowercased = BuildOptions { lowercase: true, ..BuildOptions::default() };
+        for model in [MarkovModel::train(text, 2).unwrap(), MarkovModel::train_with("The cat sat on the mat", 2, &lowercased).unwrap()] {
+            let starter = get_text_starter(&model, &mut StdRng::seed_from_u64(1)).unwrap();
+            assert!(model.chain.contains_key(&starter), "'{}' is not a state", starter);
+        }
+    }
+
+    #[test]
+    fn trimming_tidies_the_end_of_the_text() {
+        let cases = [
+            ("He said, \"", "He said"),
+            ("and then (he went", "and then he went"),
+            ("\u{201C}Quoted at length", "Quoted at length"),
+            ("it was -", "it was"),
+            ("a long wait \u{2014} ", "a long wait"),
+            ("done.\" And so,", "done.\" And so"),
+            ("here!\" he said;", "here!\" he said"),
+            ("He said: \"No (really).\"", "He said: \"No (really).\""),
+            ("It was over.", "It was over."),
+            (",", ""),
+        ];
+        for (text, trimmed) in cases {
+            assert_eq!(trim_output(text, DEFAULT_SENTENCE_TERMINATORS), trimmed, "trimming {:?}", text);
+        }
+    }
+
+    #[test]
+    fn states_are_allocated_up_front() {
+        // Two states seen a thousand times: a map grown as needed would end up with room for just a few
+        let text = "a b ".repeat(500);
+        let options = BuildOptions::default();
+        let model = CompactModel::build(&[tokenize(&text, &options)], 1, &options).unwrap();
+        assert_eq!(model.len(), 2);
+        assert!(model.states.capacity() >= 999, "room for {} states", model.states.capacity());
+
+        let mut builder = ModelBuilder::new(1, &options);
+        builder.push_line(&text);
+        let streamed = builder.finish().unwrap();
+        assert!(streamed.states.capacity() >= 1000, "room for {} states", streamed.states.capacity());
+    }
+}
@@ -1,6 +1,16 @@
-use std::{collections::HashMap, fs};
-use clap::{Parser, Subcommand};
-use rand::seq::IndexedRandom;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::{fs, io};
+use std::io::{BufRead, BufReader, IsTerminal, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use clap::parser::ValueSource;
+use markov_text::{BuildOptions, CompactModel, DEFAULT_SENTENCE_TERMINATORS, GenerateOptions, GeneratedText, InputMode, Level, Markup, MarkovError, MarkovModel, ModelBuilder, ModelFormat, PromptFallback, ProperNounFilter, StartStrategy, StopwordMode, TokenFilter, Tokenization, TraceStep, UNDERTRAINED_DEAD_ENDS, VerbatimIndex, compare_chains, detect_format, get_text_starter_with, merge_chain, model_from_bytes, model_stats, model_to_dot, ngram_frequencies, shuffle_successors, token_frequencies, tokenize_documents, validate_model};
+use rand::{SeedableRng, rngs::StdRng, seq::IndexedRandom};
+use serde::{Deserialize, Serialize};
 
 /// A CLI program to quickly generate text using Markov chains, based on some input text.
 #[derive(Parser, Debug)]
@@ -8,255 +18,1663 @@ use rand::seq::IndexedRandom;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Print every step of model construction and text generation to stderr.
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// Don't print progress messages, only the generated text (or JSON) and errors.
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Print how long building the model and generating text took to stderr.
+    #[arg(long, global = true)]
+    time: bool,
+
+    /// Read default options from this file instead of `markov.toml` in the working directory.
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<String>,
+}
+
+/// What the CLI reports about its progress besides its actual output.
+#[derive(Clone, Copy, Debug)]
+struct Report {
+    /// Leave out progress messages, see `--quiet`.
+    quiet: bool,
+    /// Print how long each phase took, see `--time`.
+    time: bool,
+}
+
+/// The input files a model is built from, and how to read them.
+#[derive(Clone, Copy, Debug)]
+struct Sources<'a> {
+    /// Paths of the input files, `-` for stdin, each optionally followed by `:weight`, see [`weighted_path`].
+    paths: &'a [String],
+    /// Replace invalid UTF-8 instead of failing, see `--lossy`.
+    lossy: bool,
+    /// Whether the files are plain text or JSON Lines, see `--input-format`.
+    format: InputFormat,
+    /// The field of each JSON Lines record that holds its text, see `--text-field`.
+    text_field: &'a str,
+}
+
+impl<'a> Sources<'a> {
+    /// Plain text files, read strictly.
+    fn text(paths: &'a [String]) -> Self {
+        Sources { paths, lossy: false, format: InputFormat::Text, text_field: "" }
+    }
+}
+
+/// What `generate` does with the chain it built, besides saving it.
+#[derive(Clone, Copy, Debug)]
+struct AfterBuild {
+    /// Drop successors seen fewer times than this, see `--min-count`.
+    min_count: Option<u32>,
+    /// Keep only this many successors per state, see `--max-successors`.
+    max_successors: Option<usize>,
+    /// Print a sample of this many words once the model is saved, see `--preview`.
+    preview: Option<usize>,
+}
+
+/// Generation worked, but some samples came out too short to be of use, see [`EXIT_SHORT_OUTPUT`].
+#[derive(Debug)]
+struct ShortOutput {
+    /// Number of samples that are too short.
+    short: usize,
+    /// Number of samples generated.
+    total: usize,
+    /// The `--min-words` they fell short of, if any.
+    min_words: Option<usize>,
+    /// What a token of the model is, for the message.
+    unit: &'static str,
+}
+
+impl std::fmt::Display for ShortOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.total, self.min_words) {
+            (1, Some(min_words)) => write!(f, "The generated text has fewer than {} {}", min_words, self.unit),
+            (1, None) => write!(f, "The generated text hit a dead-end before getting past its start"),
+            (_, Some(min_words)) => write!(f, "{} of {} samples have fewer than {} {}", self.short, self.total, min_words, self.unit),
+            (_, None) => write!(f, "{} of {} samples hit a dead-end before getting past their start", self.short, self.total),
+        }
+    }
+}
+
+impl std::error::Error for ShortOutput {}
+
+/// Exit status for [`ShortOutput`], so scripts can tell it apart from success (0) and from errors (1).
+const EXIT_SHORT_OUTPUT: i32 = 2;
+
+/// Generation was stopped by Ctrl-C after printing what it had so far, see [`EXIT_INTERRUPTED`].
+#[derive(Debug)]
+struct Interrupted {
+    /// Number of samples finished before the interrupt.
+    complete: usize,
+}
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.complete {
+            1 => write!(f, "Interrupted after 1 complete sample"),
+            complete => write!(f, "Interrupted after {} complete samples", complete),
+        }
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Exit status for [`Interrupted`], the one shells report for a process killed by Ctrl-C.
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// The file default options are read from, unless `--config` names another one.
+const CONFIG_FILE: &str = "markov.toml";
+
+/// How many attempts `--unique` may make per requested sample before settling for fewer.
+const UNIQUE_ATTEMPTS_PER_SAMPLE: u64 = 10;
+
+/// The largest state size `profile` builds a model for.
+const MAX_PROFILE_STATE_SIZE: usize = 5;
+
+/// Default options from the config file. Options given on the command line take precedence.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Config {
+    /// State size for `generate` and `text`.
+    state_size: Option<usize>,
+    /// Sampling temperature for `model`, `text`, `repl` and `continue`.
+    temperature: Option<f64>,
+    /// Top-k sampling for `model`, `text`, `repl` and `continue`, unless `--top-p` is given.
+    top_k: Option<usize>,
+    /// Random seed for `model`, `text`, `repl`, `continue`, `next` and `starter`.
+    seed: Option<u64>,
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Generate a Markov chain model from the input text.
     Generate {
-        /// The input text file to read from.
-        #[arg(short, long)]
-        input: String,
+        /// The input text file to read from, or `-` for stdin. Can be given several times to train on all of them,
+        /// and followed by `:N` to count its transitions N times, e.g. `a.txt:3`.
+        #[arg(short, long, required = true)]
+        input: Vec<String>,
 
         /// The output file to write the generated model to.
         output: String,
         
-        /// The size of the state to use for the Markov chain.
+        /// The size of the state to use for the Markov chain, i.e. its order. 0 samples every word independently.
         #[arg(default_value_t = 2)]
         state_size: usize,
+
+        /// The order of the Markov chain, instead of giving it as the state size.
+        #[arg(long, value_name = "N", conflicts_with = "state_size")]
+        order: Option<usize>,
+
+        /// Drop next words seen fewer than N times after a state, and states left with none.
+        #[arg(long, value_name = "N")]
+        min_count: Option<u32>,
+
+        /// Keep only the N most frequent next words of each state.
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+        max_successors: Option<u64>,
+
+        /// After writing the model, print a sample of N words generated from it, as `model` would.
+        #[arg(long, value_name = "N")]
+        preview: Option<usize>,
+
+        #[command(flatten)]
+        save: SaveArgs,
+
+        #[command(flatten)]
+        build: BuildArgs,
     },
     /// Generate text based on the Markov chain model.
     Model {
-        /// The input file containing the Markov chain model.
+        /// The input file containing the Markov chain model, in any supported format, or '-' for stdin.
         input: String,
 
-        /// The number of words to generate.
+        /// The number of words (or characters, for character-level models) to generate.
         #[arg(default_value_t = 100)]
         max_words: usize,
 
-        /// The size of the state to use for text generation.
-        #[arg(default_value_t = 2)]
-        state_size: usize,
+        /// The state size the model is expected to have been built with. Read from the model file if omitted.
+        #[arg(long, visible_alias = "order")]
+        state_size: Option<usize>,
+
+        #[command(flatten)]
+        generation: GenerationArgs,
     },
     /// Gnerate text from input text.
     Text {
-        /// The input text file to read from.
+        /// The input text file to read from, or `-` for stdin.
         input: String,
-        /// The number of words to generate.
+        /// Additional input text files to train on together with the first one.
+        #[arg(short = 'i', long = "input")]
+        extra_inputs: Vec<String>,
+        /// The number of words (or characters, for character-level models) to generate.
         #[arg(default_value_t = 100)]
         max_words: usize,
-        /// The size of the state to use for text generation.
+        /// The size of the state to use for text generation, i.e. its order. 0 samples every word independently.
         #[arg(default_value_t = 2)]
         state_size: usize,
+        /// The order of the Markov chain, instead of giving it as the state size.
+        #[arg(long, value_name = "N", conflicts_with = "state_size")]
+        order: Option<usize>,
+        #[command(flatten)]
+        build: BuildArgs,
+        #[command(flatten)]
+        generation: GenerationArgs,
+    },
+    /// Load a model once and generate a sample for every prompt read from stdin, until end of input.
+    Repl {
+        /// The input file containing the Markov chain model, in any supported format.
+        input: String,
+
+        /// The number of words (or characters, for character-level models) to generate for each prompt.
+        #[arg(long, default_value_t = 100)]
+        max_words: usize,
+
+        #[command(flatten)]
+        generation: GenerationArgs,
+    },
+    /// Continue a text file with a model, printing only the continuation.
+    Continue {
+        /// The input file containing the Markov chain model, in any supported format.
+        model: String,
+
+        /// The text file to continue, or `-` for stdin. Its last words are the state generation starts from.
+        seed_text: String,
+
+        /// The number of words (or characters, for character-level models) to add to the text.
+        #[arg(default_value_t = 100)]
+        max_words: usize,
+
+        #[command(flatten)]
+        generation: GenerationArgs,
+    },
+    /// Combine several Markov chain models into one, adding up their transition counts.
+    Merge {
+        /// The model files to merge. All must have the same state size and level.
+        #[arg(required = true, num_args = 2..)]
+        inputs: Vec<String>,
+
+        /// The output file to write the merged model to.
+        output: String,
+
+        #[command(flatten)]
+        save: SaveArgs,
+    },
+    /// Print statistics about a Markov chain model.
+    Stats {
+        /// The input file containing the Markov chain model, in any supported format.
+        input: String,
+
+        /// Print the statistics as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Report how many states two Markov chain models have in common, e.g. before merging them.
+    Compare {
+        /// The first model file, in any supported format.
+        a: String,
+
+        /// The second model file, in any supported format.
+        b: String,
+
+        /// Print the comparison as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the most frequent words of an input text, without building a model.
+    WordFreq {
+        /// The input text file to read from, or `-` for stdin.
+        input: String,
+
+        /// The number of words to print.
+        #[arg(default_value_t = 20)]
+        top: usize,
+
+        #[command(flatten)]
+        tokenize: TokenizeArgs,
+    },
+    /// Print the most frequent runs of N consecutive words of an input text, e.g. bigrams for N = 2.
+    Ngrams {
+        /// The input text file to read from, or `-` for stdin.
+        input: String,
+
+        /// The number of words in each n-gram.
+        #[arg(default_value_t = 2, value_parser = clap::value_parser!(u64).range(1..))]
+        n: u64,
+
+        /// The number of n-grams to print.
+        #[arg(default_value_t = 20)]
+        top: usize,
+
+        /// Print the n-grams and their counts as JSON.
+        #[arg(long)]
+        json: bool,
+
+        #[command(flatten)]
+        tokenize: TokenizeArgs,
+    },
+    /// Suggest a state size for an input text, from how many single-successor states models of each size would have.
+    Profile {
+        /// The input text file to read from, or `-` for stdin.
+        input: String,
+
+        /// Recommend the largest state size where at most this fraction of the states have a single successor.
+        /// By default that's the fraction above which building a model warns about it.
+        #[arg(long, value_name = "FRACTION", default_value_t = UNDERTRAINED_DEAD_ENDS)]
+        max_dead_ends: f64,
+
+        /// Print the profile as JSON.
+        #[arg(long)]
+        json: bool,
+
+        #[command(flatten)]
+        tokenize: TokenizeArgs,
+    },
+    /// Add more text to an existing Markov chain model, with the settings it was built with.
+    Update {
+        /// The model file to update.
+        model: String,
+
+        /// The text file to add to the model ("-" for stdin).
+        input: String,
+
+        /// Write the updated model to this file instead of overwriting the original.
+        #[arg(short, long)]
+        output: Option<String>,
+
+        /// Replace bytes that aren't valid UTF-8 in the text instead of failing.
+        #[arg(long)]
+        lossy: bool,
+
+        #[command(flatten)]
+        save: SaveArgs,
+    },
+    /// Check a Markov chain model file for problems without generating from it.
+    Validate {
+        /// The input file containing the Markov chain model, in any supported format ("-" for stdin).
+        input: String,
+    },
+    /// Show the possible next words of a single state, and pick one of them at random.
+    Next {
+        /// The input file containing the Markov chain model, in any supported format.
+        input: String,
+
+        /// The state to look up, e.g. "the man" for a model with state size 2.
+        state: String,
+
+        /// Seed for the random number generator, for reproducible output.
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+    /// Print a single state to start generating from, picked at random, and nothing else.
+    Starter {
+        /// The input file containing the Markov chain model, in any supported format.
+        input: String,
+
+        /// Seed for the random number generator. The same seed picks the same starter as `model` does.
+        #[arg(long)]
+        seed: Option<u64>,
+
+        /// Which states may be picked: capitalized ones, ones that followed the end of a sentence, or any.
+        #[arg(long, value_enum, default_value_t = StartStrategy::Capital)]
+        start_strategy: StartStrategy,
+
+        /// Pick the state in proportion to how often it started a sentence in the training text.
+        #[arg(long)]
+        weighted_start: bool,
+
+        /// Characters that end a sentence for --start-strategy sentence and --weighted-start.
+        #[arg(long, default_value = DEFAULT_SENTENCE_TERMINATORS)]
+        sentence_terminators: String,
+    },
+    /// Export a Markov chain model as a GraphViz DOT graph.
+    Dot {
+        /// The input file containing the Markov chain model, in any supported format.
+        input: String,
+
+        /// The file to write the DOT graph to.
+        output: String,
+
+        /// Leave out transitions seen fewer than N times.
+        #[arg(long, value_name = "N", default_value_t = 1)]
+        min_count: u32,
+    },
+}
+
+/// Options controlling how a model is built from input text.
+#[derive(Args, Debug)]
+struct BuildArgs {
+    /// Whether the chain is built over words or individual characters.
+    #[arg(long, value_enum, default_value_t = Level::Word)]
+    level: Level,
+    /// How words are split: on whitespace only, or with punctuation as separate tokens.
+    #[arg(long = "tokenize", value_enum, default_value_t = Tokenization::Whitespace)]
+    tokenization: Tokenization,
+    /// Markup to remove from the input before tokenizing it: HTML tags and entities, or Markdown formatting.
+    #[arg(long, value_enum, default_value_t = Markup::None)]
+    strip_markup: Markup,
+    /// Leave the words listed in this file, one per line, out of the chain. Compared case-insensitively.
+    #[arg(long, value_name = "FILE")]
+    stopwords: Option<String>,
+    /// Whether stopwords are removed, joining the words around them, or split the input so nothing spans them.
+    #[arg(long, value_enum, default_value_t = StopwordMode::Remove, requires = "stopwords")]
+    stopword_mode: StopwordMode,
+    /// Leave URLs, numbers or tokens without letters out of the chain. Can be given several times, or comma-separated.
+    #[arg(long = "filter", value_name = "FILTER", value_enum, value_delimiter = ',')]
+    filters: Vec<TokenFilter>,
+    /// Whether filtered tokens are removed, joining the words around them, or split the input so nothing spans them.
+    #[arg(long, value_enum, default_value_t = StopwordMode::Remove, requires = "filters")]
+    filter_mode: StopwordMode,
+    /// Lowercase the input before building the chain, so "The" and "the" share states.
+    /// Generation then starts from states that followed the end of a sentence instead of capitalized ones.
+    #[arg(long)]
+    lowercase: bool,
+    /// Mark where each input file starts and ends, so generation starts where a file started and stops where one ended.
+    #[arg(long)]
+    sentinels: bool,
+    /// Build the chain backwards, for generating text that leads up to a --prompt. Such a model can't be used
+    /// for generating forwards, or merged with one that can.
+    #[arg(long)]
+    reverse: bool,
+    /// Treat blank lines as hard boundaries, so the model never continues from one paragraph into the next.
+    #[arg(long)]
+    respect_paragraphs: bool,
+    /// Whether the input is continuous text, or a list of separate lines such as names. With `lines`, every line
+    /// is marked with sentinels, so each generated text reads like a single line.
+    #[arg(long, value_enum, default_value_t = InputMode::Continuous)]
+    mode: InputMode,
+    /// Build the model on this many threads (0 for one per core). Only worth it for large inputs.
+    #[arg(long, value_name = "N")]
+    threads: Option<usize>,
+    /// Replace bytes that aren't valid UTF-8 in the input with U+FFFD instead of failing, e.g. for old Latin-1 corpora.
+    #[arg(long)]
+    lossy: bool,
+    /// Whether the input files are plain text, or JSON Lines with one record per line whose text is a separate
+    /// document.
+    #[arg(long, value_enum, default_value_t = InputFormat::Text)]
+    input_format: InputFormat,
+    /// The field of each JSON Lines record that holds its text.
+    #[arg(long, value_name = "NAME", default_value = "text")]
+    text_field: String,
+}
+
+impl BuildArgs {
+    /// How to read the files in `paths`.
+    fn sources<'a>(&'a self, paths: &'a [String]) -> Sources<'a> {
+        Sources { paths, lossy: self.lossy, format: self.input_format, text_field: &self.text_field }
+    }
+
+    fn options(&self, verbose: bool) -> Result<BuildOptions, Box<dyn std::error::Error>> {
+        let stopwords = match &self.stopwords {
+            Some(path) => fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read stopword file '{}': {}", path, e))?
+                .lines()
+                .map(|line| line.trim().to_lowercase())
+                .filter(|word| !word.is_empty())
+                .collect(),
+            None => HashSet::new(),
+        };
+        Ok(BuildOptions { level: self.level, tokenization: self.tokenization, strip_markup: self.strip_markup, stopwords,
+                          stopword_mode: self.stopword_mode, filters: self.filters.clone(), filter_mode: self.filter_mode,
+                          lowercase: self.lowercase, sentinels: self.sentinels || self.mode == InputMode::Lines,
+                          reverse: self.reverse, respect_paragraphs: self.respect_paragraphs, mode: self.mode,
+                          threads: self.threads, verbose })
+    }
+}
+
+/// Options controlling how input text is split into tokens for analysis, without building a model.
+#[derive(Args, Debug)]
+struct TokenizeArgs {
+    /// Whether to count words or individual characters.
+    #[arg(long, value_enum, default_value_t = Level::Word)]
+    level: Level,
+    /// How words are split: on whitespace only, or with punctuation as separate tokens.
+    #[arg(long = "tokenize", value_enum, default_value_t = Tokenization::Whitespace)]
+    tokenization: Tokenization,
+    /// Markup to remove from the input before tokenizing it: HTML tags and entities, or Markdown formatting.
+    #[arg(long, value_enum, default_value_t = Markup::None)]
+    strip_markup: Markup,
+    /// Lowercase the input first, so "The" and "the" are counted together.
+    #[arg(long)]
+    lowercase: bool,
+    /// Replace bytes that aren't valid UTF-8 in the input instead of failing.
+    #[arg(long)]
+    lossy: bool,
+}
+
+impl TokenizeArgs {
+    fn options(&self) -> BuildOptions {
+        BuildOptions { level: self.level, tokenization: self.tokenization, strip_markup: self.strip_markup, lowercase: self.lowercase,
+                       ..BuildOptions::default() }
+    }
+
+    /// Read `input` and split it into documents of tokens.
+    fn documents(&self, input: &str) -> Result<Vec<Vec<String>>, Box<dyn std::error::Error>> {
+        Ok(tokenize_documents(&read_input(input, self.lossy)?, &self.options()))
+    }
+}
+
+/// Options controlling how a model file is written.
+#[derive(Args, Debug)]
+struct SaveArgs {
+    /// The file format to write the model in [default: json, or the existing format when updating in place]
+    #[arg(long, value_enum)]
+    format: Option<ModelFormat>,
+
+    /// Gzip-compress the model. Always done when the output file name ends in `.gz`.
+    #[arg(long)]
+    compress: bool,
+}
+
+/// Options controlling how text is generated from a model.
+#[derive(Args, Debug)]
+struct GenerationArgs {
+    /// Seed for the random number generator, for reproducible output.
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Write the generated text to this file instead of printing it.
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// After reaching the word limit, keep generating until a sentence ends.
+    #[arg(long)]
+    end_at_sentence: bool,
+
+    /// Generate N sentences instead of a number of words.
+    #[arg(long, value_name = "N", conflicts_with = "max_words")]
+    sentences: Option<usize>,
+
+    /// Generate at most N characters instead of a number of words, without cutting off the last word.
+    #[arg(long, value_name = "N", conflicts_with_all = ["max_words", "sentences"])]
+    max_chars: Option<usize>,
+
+    /// Characters that end a sentence for --end-at-sentence and --sentences.
+    #[arg(long, default_value = DEFAULT_SENTENCE_TERMINATORS)]
+    sentence_terminators: String,
+
+    /// Sampling temperature: 1 follows the training counts, lower is more predictable, 0 always picks the most common next word.
+    #[arg(long, default_value_t = 1.0)]
+    temperature: f64,
+
+    /// Weight each next word by its length in characters to the power B: positive favours longer words, negative shorter ones.
+    #[arg(long, value_name = "B", default_value_t = 0.0, allow_negative_numbers = true)]
+    length_bias: f64,
+
+    /// Only choose among the N most common next words of each state.
+    #[arg(long, value_name = "N")]
+    top_k: Option<usize>,
+
+    /// Only choose among the most common next words whose combined probability first exceeds P.
+    #[arg(long, value_name = "P", conflicts_with = "top_k")]
+    top_p: Option<f64>,
+
+    /// Start the text with these words and continue from them instead of from a random state.
+    #[arg(long)]
+    prompt: Option<String>,
+
+    /// What to do when the end of the prompt isn't a state in the model.
+    #[arg(long, value_enum, default_value_t = PromptFallback::Error)]
+    prompt_fallback: PromptFallback,
+
+    /// Match the end of the prompt, and any state generation reaches, to the model's states regardless of case, e.g.
+    /// "sherlock holmes" to "Sherlock Holmes". The text is still printed as given.
+    #[arg(long)]
+    case_insensitive_lookup: bool,
+
+    /// Which states generation may start from without a prompt: capitalized ones, ones that followed the end of a
+    /// sentence, or any.
+    #[arg(long, value_enum, default_value_t = StartStrategy::Capital)]
+    start_strategy: StartStrategy,
+
+    /// Pick the starting state in proportion to how often it started a sentence in the training text.
+    #[arg(long)]
+    weighted_start: bool,
+
+    /// On a dead-end, retry with shorter and shorter states before stopping.
+    #[arg(long)]
+    backoff: bool,
+
+    /// Mix the model's order with every lower one, weighting orders 1 to the state size by these comma-separated weights.
+    #[arg(long, value_name = "WEIGHTS", value_delimiter = ',')]
+    interpolate: Option<Vec<f64>>,
+
+    /// Jump to a new random starting state when the same phrase repeats more than N times in a row.
+    #[arg(long, value_name = "N")]
+    max_repeat: Option<usize>,
+
+    /// If generation hits a dead-end before N words, continue from a new random starting state.
+    #[arg(long, value_name = "N")]
+    min_words: Option<usize>,
+
+    /// If generation hits a dead-end before the word limit, continue from a new random starting state, up to N times.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    retries: usize,
+
+    /// Never copy more than N consecutive words of the training text, which must be more than the state size.
+    /// Generating from a model file needs its training text passed with --source.
+    #[arg(long, value_name = "N")]
+    max_verbatim: Option<usize>,
+
+    /// The text a model file was trained on, for --max-verbatim. Can be given several times.
+    #[arg(long = "source", value_name = "FILE", requires = "max_verbatim")]
+    sources: Vec<String>,
+
+    /// Put this between generated words [default: a space, or nothing for character-level models]
+    #[arg(long)]
+    separator: Option<String>,
+
+    /// Uppercase the first letter of the generated text, e.g. for lowercased models or prompts.
+    #[arg(long)]
+    capitalize_start: bool,
+
+    /// Remove quotes and brackets that are never closed, and a dangling comma, dash or the like at the end of the text.
+    #[arg(long)]
+    trim_output: bool,
+
+    /// Best effort at leaving names out: drop or lowercase capitalized words that don't start a sentence.
+    #[arg(long, value_enum, value_name = "HOW")]
+    strip_proper_nouns: Option<ProperNounFilter>,
+
+    /// Print the text and how it was generated as a JSON object. Progress messages go to stderr. Same as `--format json`.
+    #[arg(long, conflicts_with = "format")]
+    json: bool,
+
+    /// How to print the generated text: as plain text, as JSON, or as JSON Lines written as each sample is generated.
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// Add every sampled word to the JSON output, with its state, number of candidates and probability.
+    #[arg(long)]
+    trace: bool,
+
+    /// Shuffle the next words of every state with the seeded RNG before generating, to check that the output
+    /// doesn't depend on their order. A debugging aid, so left out of --help.
+    #[arg(long, hide = true)]
+    shuffle_successors: bool,
+
+    /// Number of independent samples to generate, separated by blank lines (a JSON array with --json, or one per line
+    /// with --format jsonl).
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    count: u64,
+
+    /// Discard duplicate samples and generate new ones in their place, giving up after 10 attempts per sample.
+    #[arg(long)]
+    unique: bool,
+}
+
+/// How input files are read, see `--input-format`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum InputFormat {
+    /// Plain text.
+    Text,
+    /// One JSON object per line, each a separate document.
+    Jsonl,
+}
+
+/// How generated text is printed, see `--format`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    /// The text itself, samples separated by blank lines.
+    Text,
+    /// A JSON object with the text and how it was generated, or an array of them for several samples.
+    Json,
+    /// One JSON object per line, each written as soon as its sample is generated.
+    Jsonl,
+}
+
+impl GenerationArgs {
+    fn options(&self, max_words: usize, verbose: bool) -> Result<GenerateOptions, Box<dyn std::error::Error>> {
+        if self.trace && self.format() == OutputFormat::Text {
+            return Err("--trace needs --json or --format jsonl to print the trace".into());
+        }
+        Ok(GenerateOptions {
+            max_words,
+            end_at_sentence: self.end_at_sentence,
+            sentences: self.sentences,
+            max_chars: self.max_chars,
+            sentence_terminators: self.sentence_terminators.clone(),
+            temperature: self.temperature,
+            length_bias: self.length_bias,
+            top_k: self.top_k,
+            top_p: self.top_p,
+            prompt: self.prompt.clone(),
+            prompt_fallback: self.prompt_fallback,
+            omit_prompt: false,
+            start_strategy: self.start_strategy,
+            weighted_start: self.weighted_start,
+            backoff: self.backoff,
+            interpolate: self.interpolate.clone(),
+            max_repeat: self.max_repeat,
+            min_words: self.min_words,
+            retries: self.retries,
+            separator: self.separator.clone(),
+            capitalize_start: self.capitalize_start,
+            trim_output: self.trim_output,
+            strip_proper_nouns: self.strip_proper_nouns,
+            trace: self.trace,
+            verbose,
+            case_insensitive_lookup: self.case_insensitive_lookup,
+            interrupt: None,
+            verbatim: None,
+        })
+    }
+
+    fn format(&self) -> OutputFormat {
+        if self.json { OutputFormat::Json } else { self.format.unwrap_or(OutputFormat::Text) }
+    }
+
+    /// Whether stdout is reserved for JSON, so progress messages go to stderr.
+    fn structured(&self) -> bool {
+        self.format() != OutputFormat::Text
     }
 }
 
 fn main() {
-    let cli = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Err(e) = apply_config(&mut cli, &matches) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
 
+    let report = Report { quiet: cli.quiet, time: cli.time };
     let result = match &cli.command {
-        Commands::Generate { input, output, state_size } => {
-            generate_command(input, output, *state_size)
+        Commands::Generate { input, output, state_size, order, min_count, max_successors, preview, save, build } => {
+            let after = AfterBuild { min_count: *min_count, max_successors: max_successors.map(|max| max as usize), preview: *preview };
+            build.options(cli.verbose)
+                .and_then(|options| generate_command(build.sources(input), output, order.unwrap_or(*state_size), after, save, &options, report))
+        },
+        Commands::Model { input, max_words, state_size, generation } => {
+            generation.options(*max_words, cli.verbose)
+                .and_then(|options| model_command(input, *state_size, &options, generation, report))
+        },
+        Commands::Text { input, extra_inputs, max_words, state_size, order, build, generation } => {
+            let inputs: Vec<String> = std::iter::once(input).chain(extra_inputs).cloned().collect();
+            build.options(cli.verbose)
+                .and_then(|build_options| Ok((build_options, generation.options(*max_words, cli.verbose)?)))
+                .and_then(|(build_options, options)| text_command(build.sources(&inputs), order.unwrap_or(*state_size), &build_options, &options, generation, report))
+        },
+        Commands::Repl { input, max_words, generation } => {
+            generation.options(*max_words, cli.verbose)
+                .and_then(|options| repl_command(input, &options, generation, report))
+        },
+        Commands::Continue { model, seed_text, max_words, generation } => {
+            generation.options(*max_words, cli.verbose)
+                .and_then(|options| continue_command(model, seed_text, &options, generation, report))
+        },
+        Commands::Merge { inputs, output, save } => {
+            merge_command(inputs, output, save, report)
+        },
+        Commands::Stats { input, json } => {
+            stats_command(input, *json)
+        },
+        Commands::Compare { a, b, json } => {
+            compare_command(a, b, *json)
+        },
+        Commands::WordFreq { input, top, tokenize } => {
+            word_freq_command(input, *top, tokenize)
+        },
+        Commands::Ngrams { input, n, top, json, tokenize } => {
+            ngrams_command(input, *n as usize, *top, *json, tokenize)
+        },
+        Commands::Profile { input, max_dead_ends, json, tokenize } => {
+            profile_command(input, *max_dead_ends, *json, tokenize)
+        },
+        Commands::Update { model, input, output, lossy, save } => {
+            update_command(model, input, output.as_deref(), *lossy, save, cli.verbose, report)
+        },
+        Commands::Validate { input } => {
+            validate_command(input)
         },
-        Commands::Model { input, max_words, state_size } => {
-            model_command(input, *max_words, *state_size)
+        Commands::Next { input, state, seed } => {
+            next_command(input, state, *seed)
         },
-        Commands::Text { input, max_words, state_size } => {
-            text_command(input, *max_words, *state_size)
+        Commands::Starter { input, seed, start_strategy, weighted_start, sentence_terminators } => {
+            let options = GenerateOptions { start_strategy: *start_strategy, weighted_start: *weighted_start,
+                                            sentence_terminators: sentence_terminators.clone(), ..GenerateOptions::default() };
+            starter_command(input, &options, *seed)
+        },
+        Commands::Dot { input, output, min_count } => {
+            dot_command(input, output, *min_count, report)
         },
     };
 
     if let Err(e) = result {
+        // The text is out already, only scripts need to hear about it being short
+        if e.is::<ShortOutput>() {
+            eprintln!("Warning: {}", e);
+            std::process::exit(EXIT_SHORT_OUTPUT);
+        }
+        if e.is::<Interrupted>() {
+            eprintln!("{}", e);
+            std::process::exit(EXIT_INTERRUPTED);
+        }
         eprintln!("Error: {}", e);
         std::process::exit(1);
     }
 }
 
-fn generate_command(input: &str, output: &str, state_size: usize) -> Result<(), Box<dyn std::error::Error>> {
-    let input_text = fs::read_to_string(input)
-        .map_err(|e| format!("Failed to read input file '{}': {}", input, e))?;
-    
-    if input_text.trim().is_empty() {
-        return Err("Input file is empty".into());
+/// Fill in every option the config file sets but the command line doesn't.
+fn apply_config(cli: &mut Cli, matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let path = match cli.config.as_deref() {
+        Some(path) => path,
+        None if Path::new(CONFIG_FILE).exists() => CONFIG_FILE,
+        None => return Ok(()),
+    };
+    let config_text = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read config file '{}': {}", path, e))?;
+    let config: Config = toml::from_str(&config_text)
+        .map_err(|e| format!("Failed to parse config file '{}': {}", path, e))?;
+
+    let Some((_, matches)) = matches.subcommand() else {
+        return Ok(());
+    };
+    let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    let (state_size, generation) = match &mut cli.command {
+        Commands::Generate { state_size, .. } => (Some(state_size), None),
+        Commands::Text { state_size, generation, .. } => (Some(state_size), Some(generation)),
+        Commands::Model { generation, .. } | Commands::Repl { generation, .. } | Commands::Continue { generation, .. } => {
+            (None, Some(generation))
+        }
+        _ => (None, None),
+    };
+
+    if let Some(state_size) = state_size
+        && let Some(value) = config.state_size
+        && !from_cli("state_size") {
+        *state_size = value;
     }
-    
-    let model = generate_markov_chain(&input_text, state_size)?;
-    println!("Markov model generated with {} states.", model.len());
-    
-    let output_model = serde_json::to_string(&model)
-        .map_err(|e| format!("Failed to serialize model: {}", e))?;
-    
-    fs::write(output, output_model)
+    if let Some(generation) = generation {
+        if let Some(temperature) = config.temperature
+            && !from_cli("temperature") {
+            generation.temperature = temperature;
+        }
+        if let Some(top_k) = config.top_k
+            && !from_cli("top_k") && !from_cli("top_p") {
+            generation.top_k = Some(top_k);
+        }
+        if let Some(seed) = config.seed
+            && !from_cli("seed") {
+            generation.seed = Some(seed);
+        }
+    }
+    // These pick at random too, but take nothing else from the config file
+    if let Commands::Next { seed, .. } | Commands::Starter { seed, .. } = &mut cli.command
+        && let Some(value) = config.seed
+        && !from_cli("seed") {
+        *seed = Some(value);
+    }
+    Ok(())
+}
+
+fn generate_command(sources: Sources, output: &str, state_size: usize, after: AfterBuild, save: &SaveArgs, options: &BuildOptions, report: Report) -> Result<(), Box<dyn std::error::Error>> {
+    let mut model = build_model(sources, state_size, options, false, report)?;
+
+    if let Some(min_count) = after.min_count {
+        let pruned = model.prune(min_count);
+        status(report.quiet, false, format!("Pruned {} transitions and {} states seen fewer than {} times, {} states left.",
+                                     pruned.transitions, pruned.states, min_count, model.len()));
+        if model.is_empty() {
+            return Err(format!("No states are left after pruning with --min-count {}", min_count).into());
+        }
+    }
+    if let Some(max_successors) = after.max_successors {
+        let capped = model.cap_successors(max_successors);
+        status(report.quiet, false, format!("Dropped {} transitions beyond the {} most frequent next {} of each state.",
+                                     capped.transitions, max_successors, options.level.unit()));
+    }
+    // Saved as it is, since converting it to a `MarkovModel` first would need memory for both at once
+    model.save_as(output, save.format.unwrap_or(ModelFormat::Json), save.compress)
         .map_err(|e| format!("Failed to write model to file '{}': {}", output, e))?;
-    
-    println!("Model written to {}", output);
+    status(report.quiet, false, format!("Model written to {}", output));
+
+    if let Some(max_words) = after.preview {
+        let generated = model.into_model().generate_with(&GenerateOptions { max_words, verbose: options.verbose, ..GenerateOptions::default() },
+                                                         &mut make_rng(None))?;
+        if report.quiet {
+            println!("{}", generated.text);
+        } else {
+            println!("Here is a preview of the model:\n\n{}", generated.text);
+        }
+    }
     Ok(())
 }
 
-fn model_command(input: &str, max_words: usize, state_size: usize) -> Result<(), Box<dyn std::error::Error>> {
-    let model_data = fs::read_to_string(input)
-        .map_err(|e| format!("Failed to read model file '{}': {}", input, e))?;
-    
-    let model: HashMap<String, Vec<String>> = serde_json::from_str(&model_data)
-        .map_err(|e| format!("Failed to parse model file '{}': {}", input, e))?;
-    
-    if model.is_empty() {
-        return Err("Model is empty".into());
+fn merge_command(inputs: &[String], output: &str, save: &SaveArgs, report: Report) -> Result<(), Box<dyn std::error::Error>> {
+    let mut model = load_model(&inputs[0])?;
+    for input in &inputs[1..] {
+        let other = load_model(input)?;
+        if other.state_size != model.state_size || other.level != model.level {
+            return Err(format!("Model '{}' has state size {} {}, but '{}' has state size {} {}",
+                               input, other.state_size, other.level.unit(), inputs[0], model.state_size, model.level.unit()).into());
+        }
+        if other.tokenization != model.tokenization {
+            return Err(format!("Model '{}' uses {:?} tokenization, but '{}' uses {:?} tokenization",
+                               input, other.tokenization, inputs[0], model.tokenization).into());
+        }
+        if other.lowercase != model.lowercase {
+            return Err(format!("Model '{}' {} lowercased, but '{}' {}", input, if other.lowercase { "is" } else { "isn't" },
+                               inputs[0], if model.lowercase { "is" } else { "isn't" }).into());
+        }
+        if other.reversed != model.reversed {
+            return Err(format!("Model '{}' is built {}, but '{}' is built {}", input, if other.reversed { "backwards" } else { "forwards" },
+                               inputs[0], if model.reversed { "backwards" } else { "forwards" }).into());
+        }
+        if other.sentinels != model.sentinels {
+            return Err(format!("Model '{}' {} sentinels, but '{}' {}", input, if other.sentinels { "has" } else { "doesn't have" },
+                               inputs[0], if model.sentinels { "does" } else { "doesn't" }).into());
+        }
+        merge_chain(model.chain_mut(), other.chain);
+    }
+    status(report.quiet, false, format!("Merged {} models into {} states.", inputs.len(), model.chain.len()));
+    save_model(&model, output, save, report)
+}
+
+fn model_command(input: &str, state_size: Option<usize>, options: &GenerateOptions, generation: &GenerationArgs, report: Report) -> Result<(), Box<dyn std::error::Error>> {
+    let model = load_model(input)?;
+
+    if let Some(state_size) = state_size
+        && state_size != model.state_size {
+        return Err(format!("Model '{}' was built with state size {}, but --state-size {} was given; \
+                            leave it out to use the model's own state size",
+                           input, model.state_size, state_size).into());
     }
     
-    println!("Markov model loaded with {} states.", model.len());
-    let generated_text = generate_text(&model, state_size, max_words)?;
-    println!("Here is the generated text:\n\n{}", generated_text);
+    status(report.quiet, generation.structured(), format!("Markov model loaded with {} states.", model.chain.len()));
+    let options = verbatim_options(options, generation, Sources::text(&[]), &model.build_options())?;
+    write_samples(&model, &options, generation, report)
+}
+
+fn text_command(sources: Sources, state_size: usize, build_options: &BuildOptions, options: &GenerateOptions, generation: &GenerationArgs, report: Report) -> Result<(), Box<dyn std::error::Error>> {
+    if generation.max_verbatim.is_some() && generation.sources.is_empty() && sources.paths.iter().any(|input| input == "-") {
+        return Err("--max-verbatim can't read the input from stdin a second time, pass the training text with --source".into());
+    }
+    let model = build_model(sources, state_size, build_options, generation.structured(), report)?.into_model();
+    let options = verbatim_options(options, generation, sources, build_options)?;
+    write_samples(&model, &options, generation, report)
+}
+
+/// `options` with the index `--max-verbatim` checks generated text against, built from the `--source` files,
+/// or else from `training`, the files the model was just built from.
+fn verbatim_options(options: &GenerateOptions, generation: &GenerationArgs, training: Sources, build_options: &BuildOptions) -> Result<GenerateOptions, Box<dyn std::error::Error>> {
+    let Some(max_verbatim) = generation.max_verbatim else {
+        return Ok(options.clone());
+    };
+    let paths = if generation.sources.is_empty() { training.paths } else { &generation.sources };
+    if paths.is_empty() {
+        return Err("--max-verbatim needs the text the model was trained on, pass it with --source".into());
+    }
+    let mut documents = Vec::new();
+    for input in paths {
+        let (input, _) = weighted_path(input)?;
+        documents.extend(read_documents(input, training, build_options)?);
+    }
+    Ok(GenerateOptions { verbatim: Some(Arc::new(VerbatimIndex::new(&documents, max_verbatim))), ..options.clone() })
+}
+
+fn repl_command(input: &str, options: &GenerateOptions, generation: &GenerationArgs, report: Report) -> Result<(), Box<dyn std::error::Error>> {
+    if input == "-" {
+        return Err("Can't read the model from stdin, since the prompts are read from it".into());
+    }
+    if generation.prompt.is_some() || generation.output.is_some() {
+        return Err("--prompt and --output can't be used with repl, type the prompts on stdin instead".into());
+    }
+    let model = load_model(input)?;
+    status(report.quiet, generation.structured(), format!("Markov model loaded with {} states.", model.chain.len()));
+    let options = &verbatim_options(options, generation, Sources::text(&[]), &model.build_options())?;
+
+    let interactive = io::stdin().is_terminal();
+    if interactive && !report.quiet {
+        eprintln!("Type a prompt, or an empty line to start at random. End the input to quit.");
+    }
+    let model = shuffled(&model, generation);
+    // One RNG for the whole session, so repeating a prompt gives a new sample even with --seed
+    let mut rng = make_rng(generation.seed);
+    for line in io::stdin().lines() {
+        let line = line.map_err(|e| format!("Failed to read prompt from stdin: {}", e))?;
+        let prompt = line.trim();
+        let options = GenerateOptions {
+            prompt: (!prompt.is_empty()).then(|| prompt.to_string()),
+            ..options.clone()
+        };
+        // A prompt that doesn't fit the model shouldn't end the session
+        match collect_samples(&model, &options, generation, &mut rng, report) {
+            Ok(samples) => println!("{}", format_samples(&samples, model.state_size, generation)?),
+            Err(e) => eprintln!("Error: {}", e),
+        }
+        if interactive {
+            println!();
+        }
+    }
     Ok(())
 }
 
-fn text_command(input: &str, max_words: usize, state_size: usize) -> Result<(), Box<dyn std::error::Error>> {
-    let input_text = fs::read_to_string(input)
-        .map_err(|e| format!("Failed to read input file '{}': {}", input, e))?;
-    
-    if input_text.trim().is_empty() {
-        return Err("Input file is empty".into());
+fn continue_command(input: &str, seed_text: &str, options: &GenerateOptions, generation: &GenerationArgs, report: Report) -> Result<(), Box<dyn std::error::Error>> {
+    if input == "-" && seed_text == "-" {
+        return Err("The model and the text to continue can't both be read from stdin".into());
+    }
+    if generation.prompt.is_some() {
+        return Err("--prompt can't be used with continue, the text to continue is the prompt".into());
+    }
+    let text = read_input(seed_text, false)?;
+    let model = load_model(input)?;
+    status(report.quiet, generation.structured(), format!("Markov model loaded with {} states.", model.chain.len()));
+
+    let options = GenerateOptions {
+        prompt: Some(text),
+        omit_prompt: true,
+        ..verbatim_options(options, generation, Sources::text(&[]), &model.build_options())?
+    };
+    write_samples(&model, &options, generation, report)
+}
+
+fn stats_command(input: &str, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let model = load_model(input)?;
+    let stats = model_stats(&model.chain);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(());
+    }
+
+    println!("State size: {} {}", model.state_size, model.level.unit());
+    println!("States: {}", stats.states);
+    println!("Transitions: {}", stats.transitions);
+    println!("Successors per state: mean {:.2}, median {}, max {}",
+             stats.mean_successors, stats.median_successors, stats.max_successors);
+    println!("Dead-end states (single successor): {} ({:.1}%)",
+             stats.dead_ends, 100.0 * stats.dead_ends as f64 / stats.states as f64);
+    println!("Most connected states:");
+    for (state, successors) in &stats.most_connected {
+        println!("  {:>6}  '{}'", successors, state);
     }
-    
-    let model = generate_markov_chain(&input_text, state_size)?;
-    println!("Markov model generated with {} states.", model.len());
-    let generated_text = generate_text(&model, state_size, max_words)?;
-    println!("Here is the generated text:\n\n{}", generated_text);
     Ok(())
 }
 
-fn generate_markov_chain(input: &str, state_size: usize) -> Result<HashMap<String, Vec<String>>, Box<dyn std::error::Error>> {
-    if state_size == 0 {
-        return Err("State size must be greater than 0".into());
+fn compare_command(a: &str, b: &str, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let model_a = load_model(a)?;
+    let model_b = load_model(b)?;
+    if model_a.state_size != model_b.state_size || model_a.level != model_b.level {
+        return Err(format!("Model '{}' has state size {} {}, but '{}' has state size {} {}",
+                           b, model_b.state_size, model_b.level.unit(), a, model_a.state_size, model_a.level.unit()).into());
     }
-    
-    let mut input_vec: Vec<String> = Vec::new();
-    let mut model: HashMap<String, Vec<String>> = HashMap::new();
-
-    // Collect Words
-    for line in input.lines() {
-        for word in line.split_whitespace() {
-            if !word.is_empty() {
-                input_vec.push(word.to_string());
-            }
-        }
+    let overlap = compare_chains(&model_a.chain, &model_b.chain);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&overlap)?);
+        return Ok(());
     }
-    
-    if input_vec.len() < state_size + 1 {
-        return Err(format!("Input text has {} words, but need at least {} words for state size {}", 
-                          input_vec.len(), state_size + 1, state_size).into());
+
+    println!("Shared states: {}", overlap.shared);
+    println!("Only in '{}': {}", a, overlap.only_a);
+    println!("Only in '{}': {}", b, overlap.only_b);
+    println!("Similarity: {:.1}% of all distinct states are shared", 100.0 * overlap.similarity);
+    Ok(())
+}
+
+fn word_freq_command(input: &str, top: usize, tokenize: &TokenizeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let frequencies = token_frequencies(&tokenize.documents(input)?);
+    let total: usize = frequencies.iter().map(|(_, count)| count).sum();
+    if total == 0 {
+        return Err(format!("Input file '{}' is empty", input).into());
     }
-    
-    println!("Collected {} words from input.", input_vec.len());
-
-    // Cook up the Markov Chain with given state_size
-    for i in state_size..(input_vec.len()) {
-        let current_word = &input_vec[i];
-        let previous_words = input_vec.get(i-state_size..i)
-            .ok_or("Failed to get previous words slice")?
-            .join(" ");
-        if model.contains_key(&previous_words) {
-            model.get_mut(&previous_words)
-                .ok_or("Failed to get mutable reference to model entry")?
-                .push(current_word.to_string());
-        } else {
-            model.insert(previous_words.clone(), vec![current_word.to_string()]);
+
+    println!("{} {} in total, {} distinct:", total, tokenize.level.unit(), frequencies.len());
+    for (token, count) in frequencies.iter().take(top) {
+        println!("  {:>6}  {:>6.2}%  '{}'", count, 100.0 * *count as f64 / total as f64, token);
+    }
+    Ok(())
+}
+
+/// N-gram counts, as printed by `ngrams --json`.
+#[derive(Serialize)]
+struct NgramsOutput<'a> {
+    n: usize,
+    total: usize,
+    distinct: usize,
+    ngrams: Vec<NgramCount<'a>>,
+}
+
+#[derive(Serialize)]
+struct NgramCount<'a> {
+    ngram: &'a str,
+    count: usize,
+}
+
+fn ngrams_command(input: &str, n: usize, top: usize, json: bool, tokenize: &TokenizeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let frequencies = ngram_frequencies(&tokenize.documents(input)?, n, tokenize.level);
+    let total: usize = frequencies.iter().map(|(_, count)| count).sum();
+    if total == 0 {
+        return Err(format!("Input file '{}' has no runs of {} {}", input, n, tokenize.level.unit()).into());
+    }
+
+    if json {
+        let output = NgramsOutput {
+            n,
+            total,
+            distinct: frequencies.len(),
+            ngrams: frequencies.iter().take(top).map(|(ngram, count)| NgramCount { ngram, count: *count }).collect(),
+        };
+        println!("{}", serde_json::to_string_pretty(&output)?);
+        return Ok(());
+    }
+
+    println!("{} {}-grams in total, {} distinct:", total, n, frequencies.len());
+    for (ngram, count) in frequencies.iter().take(top) {
+        println!("  {:>6}  {:>6.2}%  '{}'", count, 100.0 * *count as f64 / total as f64, ngram);
+    }
+    Ok(())
+}
+
+/// How sparse models of each state size would be, as printed by `profile --json`.
+#[derive(Serialize)]
+struct ProfileOutput {
+    vocabulary: usize,
+    state_sizes: Vec<StateSizeProfile>,
+    recommended_state_size: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct StateSizeProfile {
+    state_size: usize,
+    states: usize,
+    single_successor_fraction: f64,
+}
+
+fn profile_command(input: &str, max_dead_ends: f64, json: bool, tokenize: &TokenizeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    if !(0.0..=1.0).contains(&max_dead_ends) {
+        return Err(format!("--max-dead-ends must be between 0 and 1, but got {}", max_dead_ends).into());
+    }
+    let documents = tokenize.documents(input)?;
+    let options = tokenize.options();
+    let mut vocabulary = 0;
+    let mut state_sizes = Vec::new();
+    for state_size in 1..=MAX_PROFILE_STATE_SIZE {
+        let model = match CompactModel::build(&documents, state_size, &options) {
+            Ok(model) => model,
+            // Too little input for this state size, and so for every larger one
+            Err(MarkovError::InsufficientWords { .. }) if !state_sizes.is_empty() => break,
+            Err(e) => return Err(e.into()),
+        };
+        vocabulary = model.vocabulary();
+        state_sizes.push(StateSizeProfile { state_size, states: model.len(), single_successor_fraction: model.dead_end_fraction() });
+    }
+    let recommended_state_size = state_sizes.iter()
+        .filter(|profile| profile.single_successor_fraction <= max_dead_ends)
+        .map(|profile| profile.state_size)
+        .max();
+
+    if json {
+        let output = ProfileOutput { vocabulary, state_sizes, recommended_state_size };
+        println!("{}", serde_json::to_string_pretty(&output)?);
+        return Ok(());
+    }
+
+    println!("Vocabulary: {} distinct {}", vocabulary, tokenize.level.unit());
+    println!("State size    States    Single successor");
+    for profile in &state_sizes {
+        println!("{:>10}  {:>8}    {:>15.1}%", profile.state_size, profile.states, 100.0 * profile.single_successor_fraction);
+    }
+    match recommended_state_size {
+        Some(state_size) => println!("Recommended state size: {} (the largest with at most {:.0}% single-successor states)",
+                                     state_size, 100.0 * max_dead_ends),
+        None => println!("No state size has at most {:.0}% single-successor states, the input is small for a Markov chain; \
+                          try state size 1 or more text", 100.0 * max_dead_ends),
+    }
+    Ok(())
+}
+
+fn update_command(model_file: &str, input: &str, output: Option<&str>, lossy: bool, save: &SaveArgs, verbose: bool, report: Report) -> Result<(), Box<dyn std::error::Error>> {
+    if model_file == "-" && output.is_none() {
+        return Err("Can't update a model read from stdin in place, use --output".into());
+    }
+    let mut model = load_model(model_file)?;
+    status(report.quiet, false, format!("Markov model loaded with {} states.", model.chain.len()));
+    if !model.has_complete_build_options() && !report.quiet {
+        eprintln!("Warning: '{}' was saved by an older version that doesn't record the markup, input mode, paragraphs, \
+                   stopwords or filters it was built with, so the new text is added as continuous text without any. \
+                   Regenerate the model if it was built with other settings.", model_file);
+    }
+
+    // The new text is built separately, so its first state doesn't continue from the end of the old text
+    let options = BuildOptions { verbose, ..model.build_options() };
+    let addition = build_model(Sources { lossy, ..Sources::text(&[input.to_string()]) }, model.state_size, &options, false, report)?;
+    merge_chain(model.chain_mut(), addition.into_model().chain);
+    status(report.quiet, false, format!("Updated model has {} states.", model.chain.len()));
+
+    match output {
+        Some(output) => save_model(&model, output, save, report),
+        None => {
+            // Updating in place keeps the file in the format it was in
+            let model_data = fs::read(model_file)
+                .map_err(|e| format!("Failed to read model file '{}': {}", model_file, e))?;
+            let (format, compressed) = detect_format(&model_data)?;
+            write_model(&model, model_file, save.format.unwrap_or(format), save.compress || compressed, report)
         }
-        println!("State: '{}', Next: '{}'", previous_words, current_word);
     }
+}
+
+fn validate_command(input: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let model_data = read_model_data(input)?;
+    let source = if input == "-" { "model from stdin".to_string() } else { format!("model file '{}'", input) };
+    let problems = validate_model(&model_data)
+        .map_err(|e| format!("Failed to parse {}: {}", source, e))?;
+
+    if problems.is_empty() {
+        println!("OK");
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    Err(format!("Model '{}' has {} problem{}", input, problems.len(), if problems.len() == 1 { "" } else { "s" }).into())
+}
 
-    println!("Markov chain construction complete. States: {}", model.len());
+fn next_command(input: &str, state: &str, seed: Option<u64>) -> Result<(), Box<dyn std::error::Error>> {
+    let model = load_model(input)?;
+    // Normalize the state the same way the training input was, e.g. lowercasing it for lowercased models
+    let tokens = model.tokenize(state);
+    if tokens.len() != model.state_size {
+        return Err(format!("State '{}' has {} {}, but the model has state size {}",
+                           state, tokens.len(), model.level.unit(), model.state_size).into());
+    }
+    let key = tokens.join(model.level.separator());
+    let successors = model.chain.get(&key)
+        .ok_or_else(|| format!("State '{}' is not in the model", key))?;
+
+    let mut sorted: Vec<&(String, u32)> = successors.iter().collect();
+    sorted.sort_by(|(a_word, a_count), (b_word, b_count)| b_count.cmp(a_count).then(a_word.cmp(b_word)));
+    let total: u32 = successors.iter().map(|(_, count)| count).sum();
+    println!("State '{}' has {} possible next {}:", key, successors.len(), model.level.unit());
+    for (word, count) in sorted {
+        println!("  {:>6}  {:>6.2}%  '{}'", count, 100.0 * *count as f64 / total as f64, word);
+    }
+
+    let (chosen, _) = successors.choose_weighted(&mut make_rng(seed), |(_, count)| *count)?;
+    println!("Sampled: '{}'", chosen);
+    Ok(())
+}
+
+fn starter_command(input: &str, options: &GenerateOptions, seed: Option<u64>) -> Result<(), Box<dyn std::error::Error>> {
+    let model = load_model(input)?;
+    println!("{}", get_text_starter_with(&model, options, &mut make_rng(seed))?);
+    Ok(())
+}
+
+fn dot_command(input: &str, output: &str, min_count: u32, report: Report) -> Result<(), Box<dyn std::error::Error>> {
+    let model = load_model(input)?;
+    fs::write(output, model_to_dot(&model, min_count))
+        .map_err(|e| format!("Failed to write graph to file '{}': {}", output, e))?;
+    status(report.quiet, false, format!("Graph written to {}", output));
+    Ok(())
+}
+
+fn save_model(model: &MarkovModel, output: &str, save: &SaveArgs, report: Report) -> Result<(), Box<dyn std::error::Error>> {
+    write_model(model, output, save.format.unwrap_or(ModelFormat::Json), save.compress, report)
+}
+
+fn write_model(model: &MarkovModel, output: &str, format: ModelFormat, compress: bool, report: Report) -> Result<(), Box<dyn std::error::Error>> {
+    model.save_as(output, format, compress)
+        .map_err(|e| format!("Failed to write model to file '{}': {}", output, e))?;
+
+    status(report.quiet, false, format!("Model written to {}", output));
+    Ok(())
+}
+
+/// The raw bytes of the model file `input`, or of all of stdin for "-".
+fn read_model_data(input: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if input == "-" {
+        // Stdin can't be read twice, so buffer all of it before detecting the format
+        let mut model_data = Vec::new();
+        io::stdin().read_to_end(&mut model_data)
+            .map_err(|e| format!("Failed to read model from stdin: {}", e))?;
+        Ok(model_data)
+    } else {
+        Ok(fs::read(input).map_err(|e| format!("Failed to read model file '{}': {}", input, e))?)
+    }
+}
+
+fn load_model(input: &str) -> Result<MarkovModel, Box<dyn std::error::Error>> {
+    let model = if input == "-" {
+        model_from_bytes(&read_model_data(input)?)
+            .map_err(|e| format!("Failed to parse model from stdin: {}", e))?
+    } else {
+        MarkovModel::load(input)
+            .map_err(|e| format!("Failed to load model file '{}': {}", input, e))?
+    };
+
+    if model.chain.is_empty() {
+        return Err("Model is empty".into());
+    }
+    Ok(model)
+}
+
+fn build_model(sources: Sources, state_size: usize, options: &BuildOptions, json: bool, report: Report) -> Result<CompactModel, Box<dyn std::error::Error>> {
+    let start = Instant::now();
+    let collected = |total: usize| status(report.quiet, json, format!("Collected {} {} from {} input{}.", total, options.level.unit(),
+                                                                  sources.paths.len(), if sources.paths.len() == 1 { "" } else { "s" }));
+
+    // Markup can span lines and threads share out the whole input, otherwise it's read a line at a time
+    let model = if options.strip_markup == Markup::None && options.threads.is_none() {
+        let mut builder = ModelBuilder::new(state_size, options);
+        for input in sources.paths {
+            let (input, weight) = weighted_path(input)?;
+            builder.set_weight(weight);
+            let mut blank = true;
+            match sources.format {
+                InputFormat::Text => {
+                    read_lines(input, sources.lossy, |line| {
+                        blank &= line.trim().is_empty();
+                        builder.push_line(line);
+                    })?;
+                    builder.end_document();
+                }
+                InputFormat::Jsonl => read_records(input, sources, |text| {
+                    blank &= text.trim().is_empty();
+                    for line in text.split_inclusive('\n') {
+                        builder.push_line(line);
+                    }
+                    builder.end_document();
+                })?,
+            }
+            if blank {
+                return Err(format!("Input file '{}' is empty", input).into());
+            }
+        }
+        collected(builder.token_count());
+        builder.finish()?
+    } else {
+        let mut documents: Vec<Vec<String>> = Vec::new();
+        let mut weights = Vec::new();
+        for input in sources.paths {
+            let (input, weight) = weighted_path(input)?;
+            let input_documents = read_documents(input, sources, options)?;
+
+            if input_documents.iter().all(Vec::is_empty) {
+                return Err(format!("Input file '{}' is empty", input).into());
+            }
+
+            documents.extend(input_documents);
+            weights.resize(documents.len(), weight);
+        }
+        collected(documents.iter().map(Vec::len).sum());
+        CompactModel::build_weighted(&documents, &weights, state_size, options)?
+    };
+    status(report.quiet, json, format!("Markov model generated with {} states, {} distinct {} and {} transitions; \
+                                        {:.1}% of the states have a single successor.",
+                                       model.len(), model.vocabulary(), options.level.unit(), model.transitions(),
+                                       100.0 * model.dead_end_fraction()));
+    if model.is_undertrained() && !report.quiet {
+        eprintln!("Warning: {:.1}% of the states have a single successor, so the generated text will mostly repeat the input \
+                   verbatim. Try a smaller state size or more input text.", 100.0 * model.dead_end_fraction());
+    }
+    if report.time {
+        eprintln!("Building the model took {:?}", start.elapsed());
+    }
     Ok(model)
 }
 
-fn generate_text(model: &HashMap<String, Vec<String>>, state_size: usize, max_words: usize) -> Result<String, Box<dyn std::error::Error>> {
-    if max_words == 0 {
-        return Err("Max words must be greater than 0".into());
+/// Split an input like `a.txt:3` into its path and the weight its transitions are counted with, 1 if there's
+/// none. A file whose name ends in a colon and a number is still read as a whole, as long as it exists.
+fn weighted_path(input: &str) -> Result<(&str, u32), Box<dyn std::error::Error>> {
+    let Some((path, weight)) = input.rsplit_once(':') else {
+        return Ok((input, 1));
+    };
+    if Path::new(input).exists() {
+        return Ok((input, 1));
     }
-    
-    let mut output_vec: Vec<String> = Vec::new();
-
-    // Generate the text based off the markov model we generated
-
-    // Get a valid starting character with a capital letter.
-    let starter = get_text_starter(model, state_size)?;
-    println!("Starter Chosen: {:#?}", starter);
-    let starter_vec: Vec<&str> = starter.split(" ").collect();
-    for i in starter_vec.iter() {
-        output_vec.push(i.to_string());
-    }
-
-    // Get next state based on starter word
-    for i in state_size..max_words {
-        let previous_words = output_vec.get(i-state_size..i)
-            .ok_or("Failed to get previous words slice")?
-            .join(" ");
-        println!("Current state: '{}'", previous_words);
-        let next_word = match model.get(&previous_words) {
-            Some(words) => {
-                let chosen = words.choose(&mut rand::rng())
-                    .ok_or("No words available for current state")?;
-                println!("Next word chosen: '{}'", chosen);
-                chosen
+    match weight.parse::<u32>() {
+        Ok(0) => Err(format!("Input '{}' has weight 0, weights must be at least 1", path).into()),
+        Ok(weight) => Ok((path, weight)),
+        Err(_) => Ok((input, 1)),
+    }
+}
+
+/// Generated text and its metadata, as printed by `--json`.
+#[derive(Serialize)]
+struct JsonOutput<'a> {
+    text: &'a str,
+    words_generated: usize,
+    starter: &'a str,
+    state_size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trace: Option<&'a [TraceStep]>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    interrupted: bool,
+}
+
+impl<'a> JsonOutput<'a> {
+    fn new(generated: &'a GeneratedText, state_size: usize, generation: &GenerationArgs) -> Self {
+        JsonOutput {
+            text: &generated.text,
+            words_generated: generated.tokens,
+            starter: &generated.starter,
+            state_size,
+            trace: generation.trace.then_some(generated.trace.as_slice()),
+            interrupted: generated.interrupted,
+        }
+    }
+}
+
+/// Generate `--count` samples from `model`, all drawing from `rng` so they differ even with `--seed`,
+/// handing each one to `emit` as soon as it's generated. Once `options` is interrupted, the sample in progress
+/// is the last one handed over.
+fn generate_samples<F>(model: &MarkovModel, options: &GenerateOptions, generation: &GenerationArgs, rng: &mut StdRng, report: Report, mut emit: F) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnMut(GeneratedText) -> Result<(), Box<dyn std::error::Error>>,
+{
+    let start = Instant::now();
+    if generation.unique {
+        let mut seen = HashSet::new();
+        let mut attempts = 0;
+        while (seen.len() as u64) < generation.count && attempts < generation.count * UNIQUE_ATTEMPTS_PER_SAMPLE
+            && !options.is_interrupted() {
+            attempts += 1;
+            let sample = model.generate_with(options, rng)?;
+            if seen.insert(sample.text.clone()) {
+                emit(sample)?;
             }
-            None => {
-                println!("No next word found for state '{}', stopping generation.", previous_words);
+        }
+        if (seen.len() as u64) < generation.count && !options.is_interrupted() {
+            eprintln!("Only {} of {} samples were unique after {} attempts.", seen.len(), generation.count, attempts);
+        }
+    } else {
+        for _ in 0..generation.count {
+            if options.is_interrupted() {
                 break;
             }
-        };
-        output_vec.push(next_word.to_string());
+            emit(model.generate_with(options, rng)?)?;
+        }
     }
+    if report.time {
+        eprintln!("Generating the text took {:?}", start.elapsed());
+    }
+    Ok(())
+}
 
-    let output = output_vec.join(" ");
-    Ok(output)
+/// [`generate_samples`] into a `Vec`.
+fn collect_samples(model: &MarkovModel, options: &GenerateOptions, generation: &GenerationArgs, rng: &mut StdRng, report: Report) -> Result<Vec<GeneratedText>, Box<dyn std::error::Error>> {
+    let mut samples = Vec::new();
+    generate_samples(model, options, generation, rng, report, |sample| {
+        samples.push(sample);
+        Ok(())
+    })?;
+    Ok(samples)
 }
 
-fn get_text_starter(model: &HashMap<String, Vec<String>>, state_size: usize) -> Result<String, Box<dyn std::error::Error>> {
-    let mut starters_all: Vec<String> = Vec::new();
+/// Generate `--count` samples from `model` and print them or write them to `--output`.
+///
+/// With `--format jsonl` every sample is written and flushed as soon as it's generated, so readers can
+/// start on the first ones before the rest are done. Otherwise they're collected and written at the end.
+///
+/// Ctrl-C stops generation and writes what there is so far, including the unfinished sample; a second one
+/// exits straight away.
+fn write_samples(model: &MarkovModel, options: &GenerateOptions, generation: &GenerationArgs, report: Report) -> Result<(), Box<dyn std::error::Error>> {
+    let model = &*shuffled(model, generation);
+    let interrupt = Arc::new(AtomicBool::new(false));
+    let handler_interrupt = Arc::clone(&interrupt);
+    ctrlc::set_handler(move || {
+        if handler_interrupt.swap(true, Ordering::Relaxed) {
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+    })?;
+    let options = &GenerateOptions { interrupt: Some(interrupt), ..options.clone() };
+    let mut rng = make_rng(generation.seed);
+    // Stopping before every token of the starting state was replaced means the text is hardly more than that state
+    let is_short = |sample: &GeneratedText| (sample.dead_end && sample.sampled <= model.state_size)
+        || options.min_words.is_some_and(|min_words| sample.tokens < min_words);
+    let short_output = |short: usize, total: usize| -> Result<(), Box<dyn std::error::Error>> {
+        if short == 0 {
+            return Ok(());
+        }
+        Err(Box::new(ShortOutput { short, total, min_words: options.min_words, unit: model.level.unit() }))
+    };
+    if generation.format() != OutputFormat::Jsonl {
+        let samples = collect_samples(model, options, generation, &mut rng, report)?;
+        emit_text(&samples, model.state_size, generation, report)?;
+        if options.is_interrupted() {
+            return Err(Box::new(Interrupted { complete: samples.iter().filter(|sample| !sample.interrupted).count() }));
+        }
+        return short_output(samples.iter().filter(|sample| is_short(sample)).count(), samples.len());
+    }
 
-    // Checks for capital letters in the start of the state
-    for (key, _value) in model.iter() {
-        if let Some(first_char) = key.chars().next() {
-            if first_char.is_uppercase() && first_char.is_alphabetic() {
-                starters_all.push(key.to_string());
-            }
+    let mut writer: Box<dyn Write> = match &generation.output {
+        Some(output) => Box::new(io::BufWriter::new(fs::File::create(output)
+            .map_err(|e| format!("Failed to write generated text to file '{}': {}", output, e))?)),
+        None => Box::new(io::stdout().lock()),
+    };
+    let (mut short, mut total, mut complete) = (0, 0, 0);
+    generate_samples(model, options, generation, &mut rng, report, |sample| {
+        serde_json::to_writer(&mut writer, &JsonOutput::new(&sample, model.state_size, generation))?;
+        writeln!(writer)?;
+        writer.flush()?;
+        short += usize::from(is_short(&sample));
+        total += 1;
+        complete += usize::from(!sample.interrupted);
+        Ok(())
+    })?;
+    if let Some(output) = &generation.output {
+        status(report.quiet, true, format!("Generated text written to {}", output));
+    }
+    if options.is_interrupted() {
+        return Err(Box::new(Interrupted { complete }));
+    }
+    short_output(short, total)
+}
+
+fn emit_text(samples: &[GeneratedText], state_size: usize, generation: &GenerationArgs, report: Report) -> Result<(), Box<dyn std::error::Error>> {
+    let generated_text = format_samples(samples, state_size, generation)?;
+    match &generation.output {
+        Some(output) => {
+            fs::write(output, generated_text)
+                .map_err(|e| format!("Failed to write generated text to file '{}': {}", output, e))?;
+            status(report.quiet, generation.structured(), format!("Generated text written to {}", output));
         }
+        None if generation.structured() || report.quiet => println!("{}", generated_text),
+        None => println!("Here is the generated text:\n\n{}", generated_text),
     }
+    Ok(())
+}
 
-    // Check that the last word in the state doesn't start with a capital letter (to prevent proper nouns)
-    let mut starters_valid: Vec<String> = Vec::new();
-    for starter in starters_all.iter() {
-        let words: Vec<&str> = starter.split_whitespace().collect();
-        if words.len() >= state_size {
-            if let Some(last_word) = words.get(state_size - 1) {
-                if let Some(first_char) = last_word.chars().next() {
-                    if !first_char.is_uppercase() || !first_char.is_alphabetic() {
-                        starters_valid.push(starter.to_string());
-                    }
-                }
+/// Samples as they're printed: separated by blank lines, as JSON with `--json`, or as JSON Lines.
+fn format_samples(samples: &[GeneratedText], state_size: usize, generation: &GenerationArgs) -> Result<String, Box<dyn std::error::Error>> {
+    let outputs = || samples.iter().map(|generated| JsonOutput::new(generated, state_size, generation));
+    let formatted = match generation.format() {
+        OutputFormat::Text => samples.iter().map(|generated| generated.text.as_str()).collect::<Vec<_>>().join("\n\n"),
+        // A single sample stays a plain object, several become an array
+        OutputFormat::Json => match outputs().collect::<Vec<_>>().as_slice() {
+            [output] => serde_json::to_string(output)?,
+            outputs => serde_json::to_string(outputs)?,
+        },
+        OutputFormat::Jsonl => outputs().map(|output| serde_json::to_string(&output)).collect::<Result<Vec<_>, _>>()?.join("\n"),
+    };
+    Ok(formatted)
+}
+
+/// Print a progress message, to stderr instead of stdout when stdout is reserved for JSON output,
+/// or not at all with `--quiet`.
+fn status(quiet: bool, json: bool, message: String) {
+    if quiet {
+        return;
+    }
+    if json {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
+    }
+}
+
+/// Read an input text file, or stdin for `-`, replacing invalid UTF-8 if `lossy` is set.
+fn read_input(input: &str, lossy: bool) -> Result<String, Box<dyn std::error::Error>> {
+    let data = if input == "-" {
+        let mut data = Vec::new();
+        io::stdin().read_to_end(&mut data).map_err(|e| read_error(input, e))?;
+        data
+    } else {
+        fs::read(input).map_err(|e| read_error(input, e))?
+    };
+
+    match String::from_utf8(data) {
+        Ok(text) => Ok(text),
+        Err(e) if lossy => Ok(String::from_utf8_lossy(e.as_bytes()).into_owned()),
+        Err(e) => Err(invalid_utf8(input, e.utf8_error().valid_up_to())),
+    }
+}
+
+/// Read an input file, or stdin for `-`, and split it into documents of tokens, one per record for JSON Lines.
+fn read_documents(input: &str, sources: Sources, options: &BuildOptions) -> Result<Vec<Vec<String>>, Box<dyn std::error::Error>> {
+    match sources.format {
+        InputFormat::Text => Ok(tokenize_documents(&read_input(input, sources.lossy)?, options)),
+        InputFormat::Jsonl => {
+            let mut documents = Vec::new();
+            read_records(input, sources, |text| documents.extend(tokenize_documents(text, options)))?;
+            Ok(documents)
+        }
+    }
+}
+
+/// Hand the text of every record of a JSON Lines file, or stdin for `-`, to `push_record` as it's read, skipping
+/// blank lines.
+fn read_records(input: &str, sources: Sources, mut push_record: impl FnMut(&str)) -> Result<(), Box<dyn std::error::Error>> {
+    let mut number = 0;
+    let mut error = None;
+    read_lines(input, sources.lossy, |line| {
+        number += 1;
+        if error.is_some() || line.trim().is_empty() {
+            return;
+        }
+        let record = match serde_json::from_str::<serde_json::Value>(line) {
+            Ok(record) => record,
+            Err(e) => {
+                error = Some(format!("Line {} of '{}' is not valid JSON: {}", number, input, e));
+                return;
             }
+        };
+        match record.get(sources.text_field).and_then(serde_json::Value::as_str) {
+            Some(text) => push_record(text),
+            None => error = Some(format!("Line {} of '{}' has no string field '{}'", number, input, sources.text_field)),
+        }
+    })?;
+    error.map_or(Ok(()), |e| Err(e.into()))
+}
+
+/// Hand every line of an input text file, or stdin for `-`, to `push_line` as it's read, replacing invalid
+/// UTF-8 if `lossy` is set.
+fn read_lines(input: &str, lossy: bool, mut push_line: impl FnMut(&str)) -> Result<(), Box<dyn std::error::Error>> {
+    let mut reader: Box<dyn BufRead> = if input == "-" {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(fs::File::open(input).map_err(|e| read_error(input, e))?))
+    };
+    let mut line = Vec::new();
+    let mut offset = 0;
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line).map_err(|e| read_error(input, e))?;
+        if read == 0 {
+            return Ok(());
         }
+        match std::str::from_utf8(&line) {
+            Ok(text) => push_line(text),
+            Err(_) if lossy => push_line(&String::from_utf8_lossy(&line)),
+            Err(e) => return Err(invalid_utf8(input, offset + e.valid_up_to())),
+        }
+        offset += read;
     }
+}
 
-    // If no valid starters, use any starter with capital letter
-    if starters_valid.is_empty() {
-        starters_valid = starters_all;
+fn read_error(input: &str, e: io::Error) -> Box<dyn std::error::Error> {
+    if input == "-" {
+        format!("Failed to read input from stdin: {}", e).into()
+    } else {
+        format!("Failed to read input file '{}': {}", input, e).into()
     }
+}
 
-    // If still no starters, use any key from the model
-    if starters_valid.is_empty() {
-        if let Some((key, _)) = model.iter().next() {
-            starters_valid.push(key.clone());
-        } else {
-            return Err("Model is empty, cannot generate text".into());
-        }
+fn invalid_utf8(input: &str, offset: usize) -> Box<dyn std::error::Error> {
+    let source = if input == "-" { "Input from stdin".to_string() } else { format!("Input file '{}'", input) };
+    format!("{} is not valid UTF-8 (the first invalid byte is at offset {}); pass --lossy to replace invalid bytes",
+            source, offset).into()
+}
+
+/// `model` with its successors shuffled if `--shuffle-successors` is given, by an RNG of its own so that generation
+/// still draws the same random numbers.
+fn shuffled<'a>(model: &'a MarkovModel, generation: &GenerationArgs) -> Cow<'a, MarkovModel> {
+    if !generation.shuffle_successors {
+        return Cow::Borrowed(model);
     }
+    let mut model = model.clone();
+    shuffle_successors(model.chain_mut(), &mut make_rng(generation.seed));
+    Cow::Owned(model)
+}
 
-    // Randomly pick 1 to be the starter
-    let mut random_num = rand::rng();
-    let starter = starters_valid.choose(&mut random_num)
-        .ok_or("Failed to choose a starter, please ensure that one state starts with a capital letter to generate valid sentences.")?;
-    
-    println!("Starter selected: '{}'", starter);
-    Ok(starter.clone())
-}
\ No newline at end of file
+fn make_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_os_rng(),
+    }
+}
@@ -0,0 +1,27 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Run the tool with `args`, feeding it `stdin`, and return whether it succeeded along with its stdout.
+fn run(args: &[&str], stdin: &[u8]) -> (bool, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_markov-text"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    let output = child.wait_with_output().unwrap();
+    (output.status.success(), String::from_utf8(output.stdout).unwrap())
+}
+
+#[test]
+fn validate_reads_the_model_from_stdin() {
+    let model = include_bytes!("../examples/sherlock.json");
+    assert_eq!(run(&["validate", "-"], model), (true, "OK\n".to_string()));
+
+    let broken = br#"{"version":9,"state_size":2,"states":{"the cat":{"sat":0}}}"#;
+    let (success, stdout) = run(&["validate", "-"], broken);
+    assert!(!success);
+    assert_eq!(stdout, "State 'the cat' has successor 'sat' with a count of 0\n");
+}